@@ -0,0 +1,114 @@
+extern crate rustboy;
+
+use std::io::prelude::*;
+use std::io;
+use std::fs::File;
+use std::env;
+use std::path::Path;
+use std::process;
+
+use rustboy::disasm;
+use rustboy::symbols::{self, Symbols};
+
+fn usage() -> ! {
+    println!("usage: rustboy-disasm <rom> [--bank N | --bank N-M]");
+    println!("");
+    println!("Disassembles rom banks to stdout, all of them by default. Bank 0 is");
+    println!("shown at 0000-3fff and the other banks at 4000-7fff. Labels are");
+    println!("taken from <rom>.sym if it exists.");
+    process::exit(2);
+}
+
+fn parse_banks(arg: Option<String>) -> (usize, usize) {
+    let arg = arg.unwrap_or_else(|| usage());
+    let parse = |s: &str| s.parse().unwrap_or_else(|_| usage());
+    match arg.find('-') {
+        Some(i) => (parse(&arg[..i]), parse(&arg[i + 1..])),
+        None => { let bank = parse(&arg); (bank, bank) }
+    }
+}
+
+// Labels of jump targets in the same bank or bank 0. Where a jump from bank
+// 0 into 0x4000-0x7fff ends up depends on the mapped bank, so those are left
+// alone.
+fn target_name<'a>(symbols: &'a Symbols, bank: usize, target: u16) -> Option<&'a str> {
+    match target {
+        0 ... 0x3fff => symbols.name(Some(0), target),
+        0x4000 ... 0x7fff if bank != 0 => symbols.name(Some(bank), target),
+        0x8000 ... 0xffff => symbols.name(None, target),
+        _ => None,
+    }
+}
+
+fn disassemble_bank(out: &mut Write, rom: &[u8], bank: usize, symbols: &Symbols) -> Result<(), io::Error> {
+    let data = &rom[bank * 0x4000..(bank + 1) * 0x4000];
+    let base = if bank == 0 { 0 } else { 0x4000 };
+    try!(writeln!(out, "; bank {:02x}", bank));
+    let mut i = 0;
+    while i < data.len() {
+        let pc = (base + i) as u16;
+        let (ins, len) = disasm::disassemble(&data[i..], pc);
+        let len = len.min(data.len() - i);
+        let bytes : Vec<String> = data[i..i + len].iter().map(|b| format!("{:02x}", b)).collect();
+        if let Some(name) = symbols.name(Some(bank), pc) {
+            try!(writeln!(out, "{}:", name));
+        }
+        match ins.target.and_then(|target| target_name(symbols, bank, target)) {
+            Some(name) => try!(writeln!(out, "{:02x}:{:04x}  {:<10}{}  ; {}", bank, pc, bytes.join(" "), ins, name)),
+            None => try!(writeln!(out, "{:02x}:{:04x}  {:<10}{}", bank, pc, bytes.join(" "), ins)),
+        }
+        i += len;
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut filename = None;
+    let mut banks = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--bank" => { banks = Some(parse_banks(args.next())); }
+            "-h" | "--help" => { usage(); }
+            _ => { filename = Some(arg); }
+        }
+    }
+
+    let filename = filename.unwrap_or_else(|| usage());
+    let mut rom = Vec::new();
+    if let Err(e) = File::open(&filename).and_then(|mut f| f.read_to_end(&mut rom)) {
+        println!("{}: {}", filename, e);
+        process::exit(1);
+    }
+    let count = (rom.len() + 0x3fff) / 0x4000;
+    rom.resize(count * 0x4000, 0);
+    let (first, last) = banks.unwrap_or((0, count.saturating_sub(1)));
+    if count == 0 || first > last || last >= count {
+        println!("{}: rom has {} banks", filename, count);
+        process::exit(1);
+    }
+
+    let sym_path = symbols::path(Path::new(&filename));
+    let symbols = if sym_path.exists() {
+        Symbols::load(&sym_path).unwrap_or_else(|e| {
+            println!("{}: {}", sym_path.display(), e);
+            process::exit(1);
+        })
+    } else {
+        Symbols::new()
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for bank in first..last + 1 {
+        if let Err(e) = disassemble_bank(&mut out, &rom, bank, &symbols) {
+            // most likely a closed pipe
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                println!("{}", e);
+                process::exit(1);
+            }
+            return;
+        }
+    }
+}
@@ -0,0 +1,166 @@
+extern crate rustboy;
+
+use std::io::prelude::*;
+use std::io;
+use std::fs::File;
+use std::io::BufWriter;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use rustboy::Gameboy;
+use rustboy::gameboy::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use rustboy::image;
+use rustboy::printer::Printer;
+use rustboy::trace;
+
+fn usage() -> ! {
+    println!("usage: rustboy-headless <rom> [--frames N | --cycles N] [--output FILE]");
+    println!("                        [--trace FILE [--trace-start PC] [--trace-stop PC] [--trace-labels]]");
+    println!("                        [--printer DIR] [--serial-log FILE|stdout]");
+    println!("");
+    println!("Runs the rom without a window and writes the final screen to FILE.");
+    println!("The format is picked from the extension (.png or .ppm).");
+    println!("--trace logs every instruction in the gameboy-doctor format, optionally");
+    println!("only between the two hex addresses or labels from <rom>.sym. --trace-labels");
+    println!("adds a line for every label reached, which breaks diffs against reference logs.");
+    println!("--printer connects a Game Boy Printer that saves pages as png files in DIR.");
+    println!("--serial-log copies every byte sent over the serial port, which is how");
+    println!("blargg's test roms print their results.");
+    process::exit(2);
+}
+
+fn parse_num(arg: Option<String>) -> u64 {
+    match arg.and_then(|s| s.parse().ok()) {
+        Some(n) => n,
+        None => usage(),
+    }
+}
+
+fn open_serial_log(name: &str) -> Result<Box<Write>, io::Error> {
+    if name == "stdout" {
+        return Ok(Box::new(io::stdout()));
+    }
+    Ok(Box::new(BufWriter::new(try!(File::create(name)))))
+}
+
+fn parse_pc(gb: &Gameboy, arg: Option<String>) -> Option<(Option<usize>, u16)> {
+    arg.map(|s| gb.symbols.parse_pc(&s).unwrap_or_else(|| usage()))
+}
+
+fn write_screen(filename: &str, gb: &Gameboy) -> Result<(), io::Error> {
+    let rgb = image::rgb332_to_rgb(gb.framebuffer());
+    let mut f = try!(File::create(filename));
+    if filename.ends_with(".ppm") {
+        image::write_ppm(&mut f, SCREEN_WIDTH, SCREEN_HEIGHT, &rgb)
+    } else {
+        image::write_png(&mut f, SCREEN_WIDTH, SCREEN_HEIGHT, &rgb)
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut filename = None;
+    let mut frames = 60;
+    let mut cycles = None;
+    let mut output = String::from("screen.png");
+    let mut trace_file = None;
+    let mut trace_start = None;
+    let mut trace_stop = None;
+    let mut trace_labels = false;
+    let mut printer_dir = None;
+    let mut serial_log = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--frames" => { frames = parse_num(args.next()); }
+            "--cycles" => { cycles = Some(parse_num(args.next())); }
+            "--output" => { output = args.next().unwrap_or_else(|| usage()); }
+            "--trace" => { trace_file = Some(args.next().unwrap_or_else(|| usage())); }
+            "--trace-start" => { trace_start = Some(args.next().unwrap_or_else(|| usage())); }
+            "--trace-stop" => { trace_stop = Some(args.next().unwrap_or_else(|| usage())); }
+            "--trace-labels" => { trace_labels = true; }
+            "--printer" => { printer_dir = Some(args.next().unwrap_or_else(|| usage())); }
+            "--serial-log" => { serial_log = Some(args.next().unwrap_or_else(|| usage())); }
+            "-h" | "--help" => { usage(); }
+            _ => { filename = Some(arg); }
+        }
+    }
+
+    let filename = filename.unwrap_or_else(|| usage());
+    let mut rom = Vec::new();
+    if let Err(e) = File::open(&filename).and_then(|mut f| f.read_to_end(&mut rom)) {
+        println!("{}: {}", filename, e);
+        process::exit(1);
+    }
+
+    let mut gb = match Gameboy::new(rom) {
+        Ok(gb) => gb,
+        Err(e) => {
+            println!("{}: {}", filename, e);
+            process::exit(1);
+        }
+    };
+    if let Some(dir) = printer_dir {
+        gb.connect_serial(Box::new(Printer::new(PathBuf::from(dir))));
+    }
+    if let Some(name) = serial_log {
+        match open_serial_log(&name) {
+            Ok(out) => gb.set_serial_log(out),
+            Err(e) => {
+                println!("{}: {}", name, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(e) = gb.load_symbols(Path::new(&filename)) {
+        println!("error loading symbols: {}", e);
+    }
+    if let Some(trace_file) = trace_file {
+        let start = parse_pc(&gb, trace_start);
+        let stop = parse_pc(&gb, trace_stop);
+        match File::create(&trace_file) {
+            Ok(f) => {
+                let mut trace = trace::Trace::new(Box::new(BufWriter::new(f)), start, stop);
+                trace.set_labels(trace_labels);
+                gb.set_trace(trace);
+            }
+            Err(e) => {
+                println!("{}: {}", trace_file, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut lockup = None;
+    match cycles {
+        Some(cycles) => {
+            let mut elapsed = 0u64;
+            while elapsed < cycles && lockup.is_none() {
+                let prev = gb.cycles();
+                gb.step();
+                elapsed += gb.cycles().wrapping_sub(prev) as u64;
+                lockup = gb.lockup_event();
+            }
+        }
+        None => {
+            for _ in 0..frames {
+                gb.run_frame();
+                lockup = gb.lockup_event();
+                if lockup.is_some() {
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Err(e) = write_screen(&output, &gb) {
+        println!("{}: {}", output, e);
+        process::exit(1);
+    }
+    if let Some(e) = lockup {
+        println!("{}: {}", filename, e);
+        drop(gb); // flush the trace
+        process::exit(1);
+    }
+}
@@ -0,0 +1,271 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use mbc1;
+use mbc2;
+use mbc3;
+use mbc5;
+use state;
+
+/// A cartridge controller. Addresses are the cpu addresses, 0x0000-0x7fff
+/// for rom and 0xa000-0xbfff for ram.
+pub trait Mapper {
+    fn read_rom(&mut self, addr: u16) -> u8;
+    fn write_rom(&mut self, addr: u16, val: u8);
+    fn read_ram(&mut self, addr: u16) -> u8;
+    fn write_ram(&mut self, addr: u16, val: u8);
+
+    fn ram_enabled(&self) -> bool;
+
+    /// The rom bank currently mapped at 0x4000-0x7fff.
+    fn rom_bank(&self) -> usize;
+
+    /// Writes the battery backed state.
+    fn save(&mut self, w: &mut Write) -> Result<(), io::Error>;
+
+    /// Restores the battery backed state written by `save`.
+    fn load(&mut self, r: &mut Read) -> Result<(), io::Error>;
+
+    /// Writes ram and banking registers for a save state.
+    fn save_state(&self, w: &mut state::Writer);
+
+    fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error>;
+
+    /// Returns the new rumble motor state if it changed since the last call.
+    fn rumble_event(&mut self) -> Option<bool> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    UnsupportedCartridge(u8),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::UnsupportedCartridge(val) =>
+                write!(f, "unsupported cartridge type 0x{:02x} ({})", val, cart_type_str(val)),
+        }
+    }
+}
+
+impl error::Error for LoadError {
+    fn description(&self) -> &str {
+        match *self {
+            LoadError::UnsupportedCartridge(_) => "unsupported cartridge type",
+        }
+    }
+}
+
+/// Picks the controller from the cartridge type in header byte 0x147.
+pub fn new(rom: Vec<u8>) -> Result<Box<Mapper>, LoadError> {
+    let cart_type = *rom.get(0x147).unwrap_or(&0);
+    let ram_size = ram_size(*rom.get(0x149).unwrap_or(&0));
+    match cart_type {
+        0x00 | 0x08 | 0x09 => Ok(Box::new(RomOnly::new(rom, ram_size))),
+        0x01 ... 0x03 => Ok(Box::new(mbc1::Mbc1::new(rom, ram_size))),
+        0x05 | 0x06 => Ok(Box::new(mbc2::Mbc2::new(rom))),
+        0x0f ... 0x13 => Ok(Box::new(mbc3::Mbc3::new(rom, ram_size, cart_type <= 0x10))),
+        0x19 ... 0x1e => Ok(Box::new(mbc5::Mbc5::new(rom, ram_size, cart_type >= 0x1c))),
+        _ => Err(LoadError::UnsupportedCartridge(cart_type)),
+    }
+}
+
+/// Whether the cartridge type keeps its ram alive with a battery.
+pub fn has_battery(cart_type: u8) -> bool {
+    match cart_type {
+        0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x17 | 0x1b | 0x1e | 0xff => true,
+        _ => false,
+    }
+}
+
+/// The battery save for a rom lives next to it, game.gb -> game.sav.
+pub fn save_path(rom_path: &Path) -> PathBuf {
+    rom_path.with_extension("sav")
+}
+
+/// Reads from a 16 KiB rom bank, wrapping the bank number to the rom size.
+pub fn read_bank(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    let banks = (rom.len() / 0x4000).next_power_of_two();
+    let bank = bank & (banks.max(1) - 1);
+    *rom.get(bank * 0x4000 + (addr & 0x3fff) as usize).unwrap_or(&0xff)
+}
+
+/// Offset into banked cartridge ram, wrapping to the ram size.
+pub fn ram_offset(ram: &[u8], bank: usize, addr: u16) -> usize {
+    (bank * 0x2000 + (addr - 0xa000) as usize) % ram.len()
+}
+
+/// Writing 0x0a to 0x0000-0x1fff enables cartridge ram, anything else disables it.
+pub fn ram_enable(val: u8) -> bool {
+    (val & 0xf) == 0xa
+}
+
+// 32 KiB rom with no banking and optionally 8 KiB of ram.
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly {
+            rom: rom,
+            ram: vec![0; ram_size],
+        }
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read_rom(&mut self, addr: u16) -> u8 {
+        *self.rom.get(addr as usize).unwrap_or(&0xff)
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+    }
+
+    fn read_ram(&mut self, addr: u16) -> u8 {
+        if self.ram.len() == 0 {
+            return 0xff;
+        }
+        let i = ram_offset(&self.ram, 0, addr);
+        self.ram[i]
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) {
+        if self.ram.len() == 0 {
+            return;
+        }
+        let i = ram_offset(&self.ram, 0, addr);
+        self.ram[i] = val;
+    }
+
+    fn ram_enabled(&self) -> bool {
+        true
+    }
+
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    fn save(&mut self, w: &mut Write) -> Result<(), io::Error> {
+        w.write_all(&self.ram)
+    }
+
+    fn load(&mut self, r: &mut Read) -> Result<(), io::Error> {
+        r.read_exact(&mut self.ram)
+    }
+
+    fn save_state(&self, w: &mut state::Writer) {
+        w.vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        r.vec(&mut self.ram)
+    }
+}
+
+pub fn cart_type_str(val: u8) -> &'static str {
+	match val {
+		0x00 => "ROM ONLY",
+		0x01 => "MBC1",
+		0x02 => "MBC1+RAM",
+		0x03 => "MBC1+RAM+BATTERY",
+		0x05 => "MBC2",
+		0x06 => "MBC2+BATTERY",
+		0x08 => "ROM+RAM",
+		0x09 => "ROM+RAM+BATTERY",
+		0x0B => "MMM01",
+		0x0C => "MMM01+RAM",
+		0x0D => "MMM01+RAM+BATTERY",
+		0x0F => "MBC3+TIMER+BATTERY",
+		0x10 => "MBC3+TIMER+RAM+BATTERY",
+		0x11 => "MBC3",
+		0x12 => "MBC3+RAM",
+		0x13 => "MBC3+RAM+BATTERY",
+		0x15 => "MBC4",
+		0x16 => "MBC4+RAM",
+		0x17 => "MBC4+RAM+BATTERY",
+		0x19 => "MBC5",
+		0x1A => "MBC5+RAM",
+		0x1B => "MBC5+RAM+BATTERY",
+		0x1C => "MBC5+RUMBLE",
+		0x1D => "MBC5+RUMBLE+RAM",
+		0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+		0xFC => "POCKET CAMERA",
+		0xFD => "BANDAI TAMA5",
+		0xFE => "HuC3",
+		0xFF => "HuC1+RAM+BATTERY",
+        _ => "UNKNOWN",
+	}
+}
+
+pub fn rom_size_str(val: u8) -> &'static str {
+    match val {
+        0x00 => "32KByte (no ROM banking)",
+        0x01 => "64KByte (4 banks)",
+        0x02 => "128KByte (8 banks)",
+        0x03 => "256KByte (16 banks)",
+        0x04 => "512KByte (32 banks)",
+        0x05 => "1MByte (64 banks)  - only 63 banks used by MBC1",
+        0x06 => "2MByte (128 banks) - only 125 banks used by MBC1",
+        0x07 => "4MByte (256 banks)",
+        0x52 => "1.1MByte (72 banks)",
+        0x53 => "1.2MByte (80 banks)",
+        0x54 => "1.5MByte (96 banks)",
+        _ => "UNKNOWN",
+    }
+}
+
+pub fn ram_size_str(val: u8) -> &'static str {
+    match val {
+        0x00 => "None",
+        0x01 => "2 KBytes",
+        0x02 => "8 KBytes",
+        0x03 => "32 KBytes (4 banks of 8 KBytes each)",
+        0x04 => "128 KBytes (16 banks of 8 KBytes each)",
+        0x05 => "64 KBytes (8 banks of 8 KBytes each)",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Size in bytes of the cartridge ram described by header byte 0x149.
+pub fn ram_size(val: u8) -> usize {
+    match val {
+        0x01 => 0x800,
+        0x02 => 0x2000,
+        0x03 => 0x8000,
+        0x04 => 0x20000,
+        0x05 => 0x10000,
+        _ => 0,
+    }
+}
+
+pub fn print_rom_info(rom: &Vec<u8>) {
+    let title = &rom[0x134..0x143];
+    let mut s = String::new();
+    for c in title {
+        if *c == 0 {
+            break;
+        }
+        s.push(*c as char);
+    }
+    println!("Title          = {}", s);
+    println!("CGB flag       = {:02x}", rom[0x143]);
+    println!("SGB flag       = {:02x}", rom[0x146]);
+    println!("Cartridge Type = {}", cart_type_str(rom[0x147]));
+    println!("ROM Size       = {}", rom_size_str(rom[0x148]));
+    println!("RAM Size       = {}", ram_size_str(rom[0x149]));
+}
+
+#[test]
+fn test_save_path() {
+    assert_eq!(save_path(Path::new("roms/zelda.gb")), PathBuf::from("roms/zelda.sav"));
+    assert!(has_battery(0x03));
+    assert!(!has_battery(0x01));
+}
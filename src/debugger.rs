@@ -0,0 +1,393 @@
+use std::io::prelude::*;
+use std::io;
+
+use cpu;
+use disasm;
+use gameboy;
+use gameboy::Gameboy;
+use mem;
+use symbols;
+use trace;
+
+/// A pc breakpoint. Addresses in 0x4000-0x7fff can be limited to one rom
+/// bank, written as `BANK:ADDR` on the command line. Breakpoints on a label
+/// use the label's bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub bank: Option<usize>,
+    pub addr: u16,
+}
+
+impl Breakpoint {
+    pub fn hit(&self, mm: &mem::MemoryMap, pc: u16) -> bool {
+        self.addr == pc && (self.bank.is_none() || self.bank == symbols::bank_at(mm, pc))
+    }
+}
+
+/// What the frontend should do after the debugger prompt returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Stay,
+    Resume,
+    Quit,
+}
+
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub paused: bool,
+    step_over: Option<u16>, // temporary breakpoint set by `next`
+}
+
+const HELP : &'static str = "\
+s, step [N]          execute N instructions (default 1)
+n, next              like step, but runs calls and rsts until they return
+c, continue          resume the game
+b, break [BANK:]ADDR add a breakpoint, ADDR can be a label from the .sym file
+watch ADDR [r|w|rw]  stop when the cpu reads and/or writes ADDR (default w)
+d, delete N          delete breakpoint N
+unwatch N            delete watchpoint N
+i, info              list breakpoints and watchpoints
+r, regs              show the registers
+set REG VAL          change a register (a f b c d e h l af bc de hl sp pc)
+x ADDR [LEN]         examine memory
+m ADDR VAL...        modify memory
+l, list [ADDR] [N]   disassemble N instructions (default pc, 10)
+q, quit              quit the emulator
+";
+
+fn parse_byte(s: &str) -> Option<u8> {
+    let s = s.trim_left_matches('$').trim_left_matches("0x");
+    u8::from_str_radix(s, 16).ok()
+}
+
+fn format_addr(gb: &Gameboy, addr: u16) -> String {
+    let s = match symbols::bank_at(&gb.mm, addr) {
+        Some(bank) => format!("{:02x}:{:04x}", bank, addr),
+        None => format!("{:04x}", addr),
+    };
+    match gb.symbols.lookup(&gb.mm, addr) {
+        Some(name) => format!("{} <{}>", s, name),
+        None => s,
+    }
+}
+
+// Adds the label of a jump target as a comment.
+fn format_ins(gb: &Gameboy, ins: &disasm::Instruction) -> String {
+    match ins.target.and_then(|addr| gb.symbols.lookup(&gb.mm, addr)) {
+        Some(name) => format!("{}  ; {}", ins, name),
+        None => format!("{}", ins),
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            paused: false,
+            step_over: None,
+        }
+    }
+
+    fn parse_breakpoint(&self, gb: &Gameboy, s: &str) -> Option<Breakpoint> {
+        match s.find(':') {
+            Some(i) => {
+                let bank = match usize::from_str_radix(&s[..i], 16) {
+                    Ok(bank) => bank,
+                    Err(_) => return None,
+                };
+                trace::parse_addr(&s[i + 1..]).map(|addr| Breakpoint { bank: Some(bank), addr: addr })
+            }
+            None => gb.symbols.parse_pc(s).map(|(bank, addr)| Breakpoint { bank: bank, addr: addr }),
+        }
+    }
+
+    fn at_breakpoint(&self, gb: &Gameboy) -> bool {
+        let pc = gb.cpu.registers().pc;
+        if self.step_over == Some(pc) {
+            return true;
+        }
+        self.breakpoints.iter().any(|b| b.hit(&gb.mm, pc))
+    }
+
+    /// Runs one frame like `Gameboy::run_frame`, but pauses on breakpoints,
+    /// watchpoints and cpu lockups. Returns a message saying why it stopped,
+    /// if it did.
+    pub fn run_frame(&mut self, gb: &mut Gameboy) -> Option<String> {
+        let start = gb.cycles();
+        loop {
+            let vblank = gb.step();
+            let reason = if let Some(e) = gb.lockup_event() {
+                Some(format!("{}", e))
+            } else if let Some(hit) = gb.cpu.watch_event() {
+                Some(format!("watchpoint: {} {:04x} = {:02x} at pc={}",
+                             if hit.write { "write" } else { "read" },
+                             hit.addr, hit.val, format_addr(gb, hit.pc)))
+            } else if self.at_breakpoint(gb) {
+                Some(format!("breakpoint at {}", format_addr(gb, gb.cpu.registers().pc)))
+            } else {
+                None
+            };
+            if reason.is_some() {
+                self.paused = true;
+                self.step_over = None;
+                return reason;
+            }
+            if vblank || gb.cycles().wrapping_sub(start) >= gameboy::CYCLES_PER_FRAME {
+                return None;
+            }
+        }
+    }
+
+    fn disassemble_at(&self, gb: &mut Gameboy, addr: u16) -> (disasm::Instruction, usize) {
+        let bytes = [gb.mm.read(addr),
+                     gb.mm.read(addr.wrapping_add(1)),
+                     gb.mm.read(addr.wrapping_add(2))];
+        disasm::disassemble(&bytes, addr)
+    }
+
+    fn print_regs(&self, gb: &mut Gameboy, out: &mut Write) -> Result<(), io::Error> {
+        let r = gb.cpu.registers();
+        let (ins, _) = self.disassemble_at(gb, r.pc);
+        try!(writeln!(out, "af={:02x}{:02x} bc={:02x}{:02x} de={:02x}{:02x} hl={:02x}{:02x} sp={:04x} pc={:04x} [{}{}{}{}]",
+                      r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, r.pc,
+                      if r.f & 0x80 > 0 { 'z' } else { '-' },
+                      if r.f & 0x40 > 0 { 'n' } else { '-' },
+                      if r.f & 0x20 > 0 { 'h' } else { '-' },
+                      if r.f & 0x10 > 0 { 'c' } else { '-' }));
+        writeln!(out, "{}  {}", format_addr(gb, r.pc), format_ins(gb, &ins))
+    }
+
+    fn set_register(gb: &mut Gameboy, reg: &str, val: u16) -> bool {
+        let mut r = gb.cpu.registers();
+        match reg {
+            "a" => r.a = val as u8,
+            "f" => r.f = val as u8,
+            "b" => r.b = val as u8,
+            "c" => r.c = val as u8,
+            "d" => r.d = val as u8,
+            "e" => r.e = val as u8,
+            "h" => r.h = val as u8,
+            "l" => r.l = val as u8,
+            "af" => { r.a = (val >> 8) as u8; r.f = val as u8; }
+            "bc" => { r.b = (val >> 8) as u8; r.c = val as u8; }
+            "de" => { r.d = (val >> 8) as u8; r.e = val as u8; }
+            "hl" => { r.h = (val >> 8) as u8; r.l = val as u8; }
+            "sp" => r.sp = val,
+            "pc" => r.pc = val,
+            _ => return false,
+        }
+        gb.cpu.set_registers(&r);
+        true
+    }
+
+    /// Runs a single debugger command, writing its output to `out`.
+    pub fn command(&mut self, gb: &mut Gameboy, line: &str, out: &mut Write) -> Result<Action, io::Error> {
+        let args : Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            return Ok(Action::Stay);
+        }
+        let symbols = gb.symbols.clone();
+        let addr_arg = |i: usize| args.get(i).and_then(|s| symbols.parse_addr(s));
+        let count_arg = |i: usize, default: usize| args.get(i).and_then(|s| s.parse().ok()).unwrap_or(default);
+
+        match args[0] {
+            "s" | "step" => {
+                for _ in 0..count_arg(1, 1) {
+                    gb.step();
+                    if let Some(e) = gb.lockup_event() {
+                        try!(writeln!(out, "{}", e));
+                        break;
+                    }
+                }
+                gb.cpu.watch_event();
+                try!(self.print_regs(gb, out));
+            }
+            "n" | "next" => {
+                let pc = gb.cpu.registers().pc;
+                let (ins, len) = self.disassemble_at(gb, pc);
+                if ins.text.starts_with("call") || ins.text.starts_with("rst") {
+                    self.step_over = Some(pc.wrapping_add(len as u16));
+                    self.paused = false;
+                    return Ok(Action::Resume);
+                }
+                return self.command(gb, "step", out);
+            }
+            "c" | "continue" => {
+                self.paused = false;
+                return Ok(Action::Resume);
+            }
+            "b" | "break" => {
+                match args.get(1).and_then(|s| self.parse_breakpoint(gb, s)) {
+                    Some(b) => {
+                        self.breakpoints.push(b);
+                        try!(writeln!(out, "breakpoint {} at {}", self.breakpoints.len() - 1, args[1]));
+                    }
+                    None => try!(writeln!(out, "usage: break [BANK:]ADDR | break LABEL")),
+                }
+            }
+            "watch" => {
+                let (read, write) = match args.get(2).map(|s| *s) {
+                    None | Some("w") => (false, true),
+                    Some("r") => (true, false),
+                    Some("rw") => (true, true),
+                    _ => (false, false),
+                };
+                match addr_arg(1) {
+                    Some(addr) if read || write => {
+                        gb.cpu.watchpoints.push(cpu::Watchpoint { addr: addr, read: read, write: write });
+                        try!(writeln!(out, "watchpoint {} at {:04x}", gb.cpu.watchpoints.len() - 1, addr));
+                    }
+                    _ => try!(writeln!(out, "usage: watch ADDR [r|w|rw]")),
+                }
+            }
+            "d" | "delete" => {
+                let n = count_arg(1, usize::max_value());
+                if n < self.breakpoints.len() {
+                    self.breakpoints.remove(n);
+                } else {
+                    try!(writeln!(out, "no breakpoint {}", args.get(1).unwrap_or(&"")));
+                }
+            }
+            "unwatch" => {
+                let n = count_arg(1, usize::max_value());
+                if n < gb.cpu.watchpoints.len() {
+                    gb.cpu.watchpoints.remove(n);
+                } else {
+                    try!(writeln!(out, "no watchpoint {}", args.get(1).unwrap_or(&"")));
+                }
+            }
+            "i" | "info" => {
+                for (i, b) in self.breakpoints.iter().enumerate() {
+                    match b.bank {
+                        Some(bank) => try!(writeln!(out, "breakpoint {}: {:02x}:{:04x}", i, bank, b.addr)),
+                        None => try!(writeln!(out, "breakpoint {}: {:04x}", i, b.addr)),
+                    }
+                }
+                for (i, w) in gb.cpu.watchpoints.iter().enumerate() {
+                    try!(writeln!(out, "watchpoint {}: {:04x} {}{}", i, w.addr,
+                                  if w.read { "r" } else { "" }, if w.write { "w" } else { "" }));
+                }
+            }
+            "r" | "regs" => {
+                try!(self.print_regs(gb, out));
+            }
+            "set" => {
+                let val = args.get(2).and_then(|s| trace::parse_addr(s));
+                let ok = match (args.get(1), val) {
+                    (Some(reg), Some(val)) => Debugger::set_register(gb, reg, val),
+                    _ => false,
+                };
+                if !ok {
+                    try!(writeln!(out, "usage: set REG VAL"));
+                }
+            }
+            "x" => {
+                let addr = match addr_arg(1) {
+                    Some(addr) => addr,
+                    None => { try!(writeln!(out, "usage: x ADDR [LEN]")); return Ok(Action::Stay); }
+                };
+                let len = count_arg(2, 16);
+                for row in 0..(len + 15) / 16 {
+                    let start = addr.wrapping_add(row as u16 * 16);
+                    try!(write!(out, "{:04x}:", start));
+                    for i in 0..::std::cmp::min(16, len - row * 16) {
+                        try!(write!(out, " {:02x}", gb.mm.read(start.wrapping_add(i as u16))));
+                    }
+                    try!(writeln!(out, ""));
+                }
+            }
+            "m" => {
+                let vals : Option<Vec<u8>> = args.iter().skip(2).map(|s| parse_byte(s)).collect();
+                match (addr_arg(1), vals) {
+                    (Some(addr), Some(ref vals)) if !vals.is_empty() => {
+                        for (i, val) in vals.iter().enumerate() {
+                            gb.mm.write(addr.wrapping_add(i as u16), *val);
+                        }
+                    }
+                    _ => try!(writeln!(out, "usage: m ADDR VAL...")),
+                }
+            }
+            "l" | "list" => {
+                let mut addr = addr_arg(1).unwrap_or(gb.cpu.registers().pc);
+                for _ in 0..count_arg(2, 10) {
+                    let (ins, len) = self.disassemble_at(gb, addr);
+                    try!(writeln!(out, "{}  {}", format_addr(gb, addr), format_ins(gb, &ins)));
+                    addr = addr.wrapping_add(len as u16);
+                }
+            }
+            "q" | "quit" => {
+                return Ok(Action::Quit);
+            }
+            "h" | "help" => {
+                try!(write!(out, "{}", HELP));
+            }
+            _ => {
+                try!(writeln!(out, "unknown command {}, try help", args[0]));
+            }
+        }
+        Ok(Action::Stay)
+    }
+
+    /// Reads commands until one of them resumes or quits. End of input
+    /// resumes the game.
+    pub fn prompt(&mut self, gb: &mut Gameboy, input: &mut BufRead, out: &mut Write) -> Result<Action, io::Error> {
+        self.paused = true;
+        try!(self.print_regs(gb, out));
+        loop {
+            try!(write!(out, "(rbdb) "));
+            try!(out.flush());
+            let mut line = String::new();
+            if try!(input.read_line(&mut line)) == 0 {
+                self.paused = false;
+                return Ok(Action::Resume);
+            }
+            match try!(self.command(gb, &line, out)) {
+                Action::Stay => {}
+                action => return Ok(action),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_debugger() {
+    let mut rom = vec![0; 0x8000];
+    // call $0200; inc b; jr -3 ... $0200: ld ($c000), a; ret
+    rom[0x100..0x106].copy_from_slice(&[0xcd, 0x00, 0x02, 0x04, 0x18, 0xfd]);
+    rom[0x200..0x204].copy_from_slice(&[0xea, 0x00, 0xc0, 0xc9]);
+    let mut gb = Gameboy::new(rom).unwrap();
+    let mut dbg = Debugger::new();
+    let mut out = Vec::new();
+
+    assert_eq!(dbg.command(&mut gb, "break 00:0103", &mut out).unwrap(), Action::Stay);
+    assert_eq!(dbg.command(&mut gb, "watch c000", &mut out).unwrap(), Action::Stay);
+    assert_eq!(dbg.command(&mut gb, "c", &mut out).unwrap(), Action::Resume);
+    assert!(dbg.run_frame(&mut gb).unwrap().starts_with("watchpoint: write c000"));
+    assert_eq!(gb.cpu.registers().pc, 0x203);
+    assert_eq!(dbg.run_frame(&mut gb).unwrap(), "breakpoint at 00:0103");
+
+    dbg.command(&mut gb, "set b 41", &mut out).unwrap();
+    dbg.command(&mut gb, "step", &mut out).unwrap();
+    assert_eq!(gb.cpu.registers().b, 0x42);
+    dbg.command(&mut gb, "m c001 12 34", &mut out).unwrap();
+    out.clear();
+    dbg.command(&mut gb, "x c001 2", &mut out).unwrap();
+    assert_eq!(String::from_utf8(out.clone()).unwrap(), "c001: 12 34\n");
+
+    // the bank qualified breakpoint does not fire in another bank
+    dbg.command(&mut gb, "delete 0", &mut out).unwrap();
+    dbg.command(&mut gb, "break 01:0103", &mut out).unwrap();
+    assert_eq!(dbg.run_frame(&mut gb), None);
+
+    // labels from a symbol file
+    let mut symbols = ::symbols::Symbols::new();
+    symbols.insert(0, 0x200, "Store");
+    gb.set_symbols(symbols);
+    dbg.command(&mut gb, "delete 0", &mut out).unwrap();
+    dbg.command(&mut gb, "break Store", &mut out).unwrap();
+    dbg.command(&mut gb, "set pc 100", &mut out).unwrap();
+    assert_eq!(dbg.run_frame(&mut gb).unwrap(), "breakpoint at 00:0200 <Store>");
+    out.clear();
+    dbg.command(&mut gb, "list 0100 1", &mut out).unwrap();
+    assert_eq!(String::from_utf8(out.clone()).unwrap(), "00:0100  call $0200  ; Store\n");
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+
+// SM83 disassembler. Opcodes are decoded from their bit fields instead of a
+// 512 entry table:
+//
+//   x = opcode >> 6, y = (opcode >> 3) & 7, z = opcode & 7
+//   p = y >> 1, q = y & 1
+
+const R   : [&'static str; 8] = ["b", "c", "d", "e", "h", "l", "(hl)", "a"];
+const RP  : [&'static str; 4] = ["bc", "de", "hl", "sp"];
+const RP2 : [&'static str; 4] = ["bc", "de", "hl", "af"];
+const CC  : [&'static str; 4] = ["nz", "z", "nc", "c"];
+const ALU : [&'static str; 8] = ["add a,", "adc a,", "sub", "sbc a,", "and", "xor", "or", "cp"];
+const ROT : [&'static str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub text: String,
+    /// Address jumped to by jp/jr/call/rst with a fixed target.
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    fn new(text: String) -> Instruction {
+        Instruction { text: text, target: None }
+    }
+
+    fn jump(text: String, target: u16) -> Instruction {
+        Instruction { text: text, target: Some(target) }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn byte(bytes: &[u8], i: usize) -> u8 {
+    *bytes.get(i).unwrap_or(&0)
+}
+
+fn word(bytes: &[u8], i: usize) -> u16 {
+    byte(bytes, i) as u16 | (byte(bytes, i + 1) as u16) << 8
+}
+
+fn signed(val: u8) -> String {
+    if val & 0x80 > 0 {
+        format!("-{}", (val as i8 as i16).abs())
+    } else {
+        format!("+{}", val)
+    }
+}
+
+/// Length in bytes of the instruction starting with `opcode`.
+pub fn length(opcode: u8) -> usize {
+    match opcode {
+        0x01 | 0x08 | 0x11 | 0x21 | 0x31 |
+        0xc2 | 0xc3 | 0xc4 | 0xca | 0xcc | 0xcd |
+        0xd2 | 0xd4 | 0xda | 0xdc | 0xea | 0xfa => 3,
+        0x06 | 0x0e | 0x10 | 0x16 | 0x18 | 0x1e | 0x20 | 0x26 | 0x28 | 0x2e |
+        0x30 | 0x36 | 0x38 | 0x3e | 0xc6 | 0xcb | 0xce | 0xd6 | 0xde |
+        0xe0 | 0xe6 | 0xe8 | 0xee | 0xf0 | 0xf6 | 0xf8 | 0xfe => 2,
+        _ => 1,
+    }
+}
+
+fn disassemble_cb(opcode: u8) -> Instruction {
+    let y = (opcode >> 3) & 7;
+    let r = R[(opcode & 7) as usize];
+    Instruction::new(match opcode >> 6 {
+        0 => format!("{} {}", ROT[y as usize], r),
+        1 => format!("bit {}, {}", y, r),
+        2 => format!("res {}, {}", y, r),
+        _ => format!("set {}, {}", y, r),
+    })
+}
+
+/// Decodes the instruction at the start of `bytes`, which was read from
+/// address `pc`. Missing bytes at the end of `bytes` are taken as zero.
+/// Returns the instruction and its length.
+pub fn disassemble(bytes: &[u8], pc: u16) -> (Instruction, usize) {
+    let opcode = byte(bytes, 0);
+    let len = length(opcode);
+    let n = byte(bytes, 1);
+    let nn = word(bytes, 1);
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 7) as usize;
+    let z = opcode & 7;
+    let p = y >> 1;
+    let q = y & 1;
+    // jr targets are relative to the next instruction
+    let rel = pc.wrapping_add(2).wrapping_add(n as i8 as u16);
+
+    let ins = match (x, z) {
+        (0, 0) => match y {
+            0 => Instruction::new(format!("nop")),
+            1 => Instruction::new(format!("ld (${:04x}), sp", nn)),
+            2 => Instruction::new(format!("stop")),
+            3 => Instruction::jump(format!("jr ${:04x}", rel), rel),
+            _ => Instruction::jump(format!("jr {}, ${:04x}", CC[y - 4], rel), rel),
+        },
+        (0, 1) => if q == 0 {
+            Instruction::new(format!("ld {}, ${:04x}", RP[p], nn))
+        } else {
+            Instruction::new(format!("add hl, {}", RP[p]))
+        },
+        (0, 2) => {
+            let mem = ["(bc)", "(de)", "(hl+)", "(hl-)"][p];
+            if q == 0 {
+                Instruction::new(format!("ld {}, a", mem))
+            } else {
+                Instruction::new(format!("ld a, {}", mem))
+            }
+        },
+        (0, 3) => Instruction::new(format!("{} {}", ["inc", "dec"][q], RP[p])),
+        (0, 4) => Instruction::new(format!("inc {}", R[y])),
+        (0, 5) => Instruction::new(format!("dec {}", R[y])),
+        (0, 6) => Instruction::new(format!("ld {}, ${:02x}", R[y], n)),
+        (0, _) => Instruction::new(format!("{}", ["rlca", "rrca", "rla", "rra",
+                                                 "daa", "cpl", "scf", "ccf"][y])),
+        (1, _) => if opcode == 0x76 {
+            Instruction::new(format!("halt"))
+        } else {
+            Instruction::new(format!("ld {}, {}", R[y], R[z as usize]))
+        },
+        (2, _) => Instruction::new(format!("{} {}", ALU[y], R[z as usize])),
+        (_, 0) => match y {
+            0 ... 3 => Instruction::new(format!("ret {}", CC[y])),
+            4 => Instruction::new(format!("ld ($ff00+${:02x}), a", n)),
+            5 => Instruction::new(format!("add sp, {}", signed(n))),
+            6 => Instruction::new(format!("ld a, ($ff00+${:02x})", n)),
+            _ => Instruction::new(format!("ld hl, sp{}", signed(n))),
+        },
+        (_, 1) => if q == 0 {
+            Instruction::new(format!("pop {}", RP2[p]))
+        } else {
+            Instruction::new(format!("{}", ["ret", "reti", "jp hl", "ld sp, hl"][p]))
+        },
+        (_, 2) => match y {
+            0 ... 3 => Instruction::jump(format!("jp {}, ${:04x}", CC[y], nn), nn),
+            4 => Instruction::new(format!("ld ($ff00+c), a")),
+            5 => Instruction::new(format!("ld (${:04x}), a", nn)),
+            6 => Instruction::new(format!("ld a, ($ff00+c)")),
+            _ => Instruction::new(format!("ld a, (${:04x})", nn)),
+        },
+        (_, 3) => match y {
+            0 => Instruction::jump(format!("jp ${:04x}", nn), nn),
+            1 => disassemble_cb(n),
+            6 => Instruction::new(format!("di")),
+            7 => Instruction::new(format!("ei")),
+            _ => Instruction::new(format!("db ${:02x}", opcode)),
+        },
+        (_, 4) => match y {
+            0 ... 3 => Instruction::jump(format!("call {}, ${:04x}", CC[y], nn), nn),
+            _ => Instruction::new(format!("db ${:02x}", opcode)),
+        },
+        (_, 5) => if q == 0 {
+            Instruction::new(format!("push {}", RP2[p]))
+        } else if p == 0 {
+            Instruction::jump(format!("call ${:04x}", nn), nn)
+        } else {
+            Instruction::new(format!("db ${:02x}", opcode))
+        },
+        (_, 6) => Instruction::new(format!("{} ${:02x}", ALU[y], n)),
+        (_, _) => {
+            let addr = y as u16 * 8;
+            Instruction::jump(format!("rst ${:02x}", addr), addr)
+        },
+    };
+    (ins, len)
+}
+
+#[test]
+fn test_disasm() {
+    let cases : &[(&[u8], u16, &str, usize)] = &[
+        (&[0x00], 0x100, "nop", 1),
+        (&[0x01, 0x34, 0x12], 0x100, "ld bc, $1234", 3),
+        (&[0x22], 0x100, "ld (hl+), a", 1),
+        (&[0x18, 0xfe], 0x150, "jr $0150", 2),
+        (&[0x20, 0x05], 0x150, "jr nz, $0157", 2),
+        (&[0x36, 0x42], 0x100, "ld (hl), $42", 2),
+        (&[0x76], 0x100, "halt", 1),
+        (&[0x78], 0x100, "ld a, b", 1),
+        (&[0x9e], 0x100, "sbc a, (hl)", 1),
+        (&[0xaf], 0x100, "xor a", 1),
+        (&[0xcb, 0x7e], 0x100, "bit 7, (hl)", 2),
+        (&[0xcb, 0x37], 0x100, "swap a", 2),
+        (&[0xcd, 0x00, 0x40], 0x100, "call $4000", 3),
+        (&[0xe0, 0x44], 0x100, "ld ($ff00+$44), a", 2),
+        (&[0xe8, 0xfc], 0x100, "add sp, -4", 2),
+        (&[0xf8, 0x02], 0x100, "ld hl, sp+2", 2),
+        (&[0xf5], 0x100, "push af", 1),
+        (&[0xd3], 0x100, "db $d3", 1),
+        (&[0xff], 0x100, "rst $38", 1),
+    ];
+    for &(bytes, pc, text, len) in cases {
+        let (ins, l) = disassemble(bytes, pc);
+        assert_eq!(ins.text, text);
+        assert_eq!(l, len);
+    }
+    assert_eq!(disassemble(&[0xc3, 0x50, 0x01], 0).0.target, Some(0x150));
+    assert_eq!(disassemble(&[0xc3], 0).0.text, "jp $0000");
+}
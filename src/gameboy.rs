@@ -0,0 +1,273 @@
+use std::cell::RefCell;
+use std::io::prelude::*;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use cartridge;
+use cpu;
+use lcd;
+use timer;
+use mem;
+use joypad;
+use serial;
+use sound;
+use state;
+use symbols;
+use trace;
+
+pub const SCREEN_WIDTH  : usize = 160;
+pub const SCREEN_HEIGHT : usize = 144;
+
+// Number of cycles the lcd takes to draw one full frame.
+pub const CYCLES_PER_FRAME : u32 = 70224;
+
+pub struct Gameboy {
+    pub cpu: cpu::Cpu,
+    pub mm: mem::MemoryMap,
+    pub lcd : Rc<RefCell<lcd::Lcd>>,
+    pub timer : Rc<RefCell<timer::Timer>>,
+    pub joypad : Rc<RefCell<joypad::Joypad>>,
+    pub sound : Arc<RwLock<sound::Sound>>,
+    pub symbols : Rc<symbols::Symbols>,
+    player : sound::SoundPlayer,
+    pixels : [u8; SCREEN_WIDTH*SCREEN_HEIGHT],
+    prevcycles : u32,
+    battery : bool,
+}
+
+impl Gameboy {
+    pub fn new(rom: Vec<u8>) -> Result<Gameboy, cartridge::LoadError> {
+        let battery = cartridge::has_battery(*rom.get(0x147).unwrap_or(&0));
+        let cart = try!(cartridge::new(rom));
+        let cpu = cpu::Cpu::new();
+        let lcd = Rc::new(RefCell::new(lcd::Lcd::new()));
+        let timer = Rc::new(RefCell::new(timer::Timer::new()));
+        let joypad = Rc::new(RefCell::new(joypad::Joypad::new()));
+        let sound = Arc::new(RwLock::new(sound::Sound::new()));
+        let mm = mem::MemoryMap::new(cart, lcd.clone(), timer.clone(),
+                                     joypad.clone(), sound.clone());
+        Ok(Gameboy {
+            cpu: cpu,
+            mm: mm,
+            lcd: lcd,
+            timer: timer,
+            joypad: joypad,
+            player: sound::SoundPlayer::new(sound.clone(), 44100),
+            sound: sound,
+            symbols: Rc::new(symbols::Symbols::new()),
+            pixels: [255; SCREEN_WIDTH*SCREEN_HEIGHT],
+            prevcycles: 0,
+            battery: battery,
+        })
+    }
+
+    /// Uses `<rom>.sav` next to the rom for battery ram and loads it if it
+    /// exists. Does nothing for carts without a battery.
+    pub fn load_battery(&mut self, rom_path: &Path) -> Result<(), io::Error> {
+        if !self.battery {
+            return Ok(());
+        }
+        let path = cartridge::save_path(rom_path);
+        let exists = path.exists();
+        self.mm.save_path = Some(path);
+        if exists {
+            try!(self.mm.load_eram());
+        }
+        Ok(())
+    }
+
+    /// Loads `<rom>.sym` next to the rom if it exists. Returns the number
+    /// of labels.
+    pub fn load_symbols(&mut self, rom_path: &Path) -> Result<usize, io::Error> {
+        let path = symbols::path(rom_path);
+        if !path.exists() {
+            return Ok(0);
+        }
+        let symbols = try!(symbols::Symbols::load(&path));
+        let count = symbols.len();
+        self.set_symbols(symbols);
+        Ok(count)
+    }
+
+    /// Uses `symbols` for labels in the trace log and the debuggers.
+    pub fn set_symbols(&mut self, symbols: symbols::Symbols) {
+        self.symbols = Rc::new(symbols);
+        if let Some(ref mut trace) = self.cpu.trace {
+            trace.set_symbols(self.symbols.clone());
+        }
+    }
+
+    /// Writes battery ram to the save file. Call this before exiting.
+    pub fn save_battery(&mut self) -> Result<(), io::Error> {
+        self.mm.save_eram()
+    }
+
+    /// Serializes the whole machine.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = state::Writer::new();
+        w.bytes(state::STATE_MAGIC);
+        w.u32(state::STATE_VERSION);
+        w.u32(self.prevcycles);
+        self.cpu.save_state(&mut w);
+        self.mm.save_state(&mut w);
+        self.lcd.borrow().save_state(&mut w);
+        self.timer.borrow().save_state(&mut w);
+        self.joypad.borrow().save_state(&mut w);
+        self.sound.read().unwrap().save_state(&mut w);
+        w.into_inner()
+    }
+
+    /// Restores a state written by `save_state`. On error the machine is
+    /// left as it was.
+    pub fn load_state(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        let backup = self.save_state();
+        let result = self.restore_state(buf);
+        if result.is_err() {
+            self.restore_state(&backup).expect("restoring the previous state");
+        }
+        result
+    }
+
+    fn restore_state(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        let mut r = state::Reader::new(buf);
+        try!(r.header());
+        self.prevcycles = try!(r.u32());
+        try!(self.cpu.load_state(&mut r));
+        try!(self.mm.load_state(&mut r));
+        try!(self.lcd.borrow_mut().load_state(&mut r));
+        try!(self.timer.borrow_mut().load_state(&mut r));
+        try!(self.joypad.borrow_mut().load_state(&mut r));
+        try!(self.sound.write().unwrap().load_state(&mut r));
+        r.finish()
+    }
+
+    /// Executes a single instruction. The cpu ticks the rest of the system
+    /// as it accesses memory. Returns true if the lcd entered vblank.
+    pub fn step(&mut self) -> bool {
+        self.prevcycles = self.cpu.run(&mut self.mm);
+        let vblank = self.mm.vblank;
+        if vblank {
+            self.mm.vblank = false;
+            self.pixels.copy_from_slice(self.lcd.borrow().pixels());
+        }
+        vblank
+    }
+
+    /// Runs until the next vblank. Gives up after one frame worth of cycles
+    /// so a game that turned off the lcd still returns control.
+    pub fn run_frame(&mut self) {
+        let start = self.prevcycles;
+        while !self.step() {
+            if self.prevcycles.wrapping_sub(start) >= CYCLES_PER_FRAME {
+                break;
+            }
+        }
+    }
+
+    /// Total cycles executed so far.
+    pub fn cycles(&self) -> u32 {
+        self.prevcycles
+    }
+
+    /// The screen, one RGB332 byte per pixel.
+    pub fn framebuffer(&self) -> &[u8; SCREEN_WIDTH*SCREEN_HEIGHT] {
+        &self.pixels
+    }
+
+    pub fn press(&mut self, button: joypad::Button) {
+        self.joypad.borrow_mut().handle_input(&mut self.mm, button, true);
+    }
+
+    pub fn release(&mut self, button: joypad::Button) {
+        self.joypad.borrow_mut().handle_input(&mut self.mm, button, false);
+    }
+
+    /// Returns the new rumble motor state if the game switched it since the
+    /// last call.
+    pub fn rumble_event(&mut self) -> Option<bool> {
+        self.mm.cart.rumble_event()
+    }
+
+    /// Plugs `device` into the link port. Without one, transfers read 0xff.
+    pub fn connect_serial(&mut self, device: Box<serial::SerialDevice>) {
+        self.mm.serial.connect(device);
+    }
+
+    /// Copies every byte sent over the serial port to `out`. Test roms and
+    /// homebrew print their diagnostics this way.
+    pub fn set_serial_log(&mut self, out: Box<Write>) {
+        self.mm.serial.set_log(out);
+    }
+
+    /// Logs every executed instruction to `trace` until it is replaced.
+    pub fn set_trace(&mut self, mut trace: trace::Trace) {
+        trace.set_symbols(self.symbols.clone());
+        self.cpu.trace = Some(trace);
+    }
+
+    /// Returns the illegal opcode if the cpu locked up since the last call.
+    /// The machine keeps running with the cpu hung, so the frontend can
+    /// still save a state or inspect memory.
+    pub fn lockup_event(&mut self) -> Option<cpu::IllegalOpcode> {
+        self.cpu.lockup_event()
+    }
+
+    /// Fills `out` with mono samples at 44100 Hz.
+    pub fn audio_samples(&mut self, out: &mut [f32]) {
+        self.player.fill(out);
+    }
+}
+
+#[test]
+fn test_gameboy() {
+    let gb = Gameboy::new(vec![0; 0x8000]).unwrap();
+    assert_eq!(gb.framebuffer()[0], 255);
+}
+
+#[test]
+fn test_gameboy_unsupported_cartridge() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x147] = 0xfd;
+    assert!(Gameboy::new(rom).is_err());
+}
+
+#[test]
+fn test_gameboy_save_state() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100] = 0x3c; // inc a
+    rom[0x101] = 0x18; // jr -3
+    rom[0x102] = 0xfd;
+    let mut gb = Gameboy::new(rom.clone()).unwrap();
+    gb.run_frame();
+    gb.mm.write(0xc000, 0x42);
+    // a serial transfer halfway through and a vblank not picked up yet
+    gb.mm.write(0xff01, 0x99);
+    gb.mm.write(0xff02, 0x81);
+    gb.mm.tick(2048);
+    gb.mm.vblank = true;
+    let state = gb.save_state();
+
+    let mut gb2 = Gameboy::new(rom).unwrap();
+    gb2.load_state(&state).unwrap();
+    assert_eq!(gb2.save_state(), state);
+    assert_eq!(gb2.mm.read(0xc000), 0x42);
+    assert!(gb2.mm.vblank);
+    assert_eq!(gb2.mm.read(0xff02), 0xff);
+    gb2.mm.tick(2048);
+    assert_eq!(gb2.mm.read(0xff02), 0x7f);
+    assert_eq!(gb2.mm.read(0xff01), 0xff);
+
+    let mut long = state.clone();
+    long.push(0);
+    assert!(gb2.load_state(&long).is_err());
+    assert!(gb2.load_state(&state[..10]).is_err());
+
+    // a truncated state leaves the machine alone
+    gb2.run_frame();
+    let before = gb2.save_state();
+    assert!(gb2.load_state(&state[..state.len() - 1]).is_err());
+    assert_eq!(gb2.save_state(), before);
+}
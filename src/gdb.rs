@@ -0,0 +1,445 @@
+use std::io::prelude::*;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+
+use debugger::Breakpoint;
+use gameboy;
+use gameboy::Gameboy;
+use symbols;
+
+// GDB remote serial protocol stub. gdb has no sm83 target, but its z80 one
+// starts with the same register pairs: af, bc, de, hl, sp and pc as 16 bit
+// little endian values in that order (`g` returns 24 hex digits, `p0`-`p5`
+// one register each). The target description we serve names the z80
+// architecture and those six registers, gdb treats the z80 only ones (ix,
+// iy, the shadow set) as unavailable.
+//
+// gdb addresses have no rom bank, so a breakpoint in 0x4000-0x7fff only
+// fires in the bank that was mapped when gdb set it. Removing one drops it
+// in every bank.
+//
+// Supported packets: ? g G p P m M c s Z0/z0 Z1/z1 qSupported qAttached
+// qXfer:features:read D k, plus ctrl-c (0x03) to stop a running target.
+// Everything else gets the empty "unsupported" reply.
+
+const SIGINT  : u8 = 2;
+const SIGILL  : u8 = 4;
+const SIGTRAP : u8 = 5;
+
+// The PacketSize we advertise. `m` replies must fit in it.
+const PACKET_SIZE : usize = 0x1000;
+
+const TARGET_XML : &'static str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>z80</architecture>
+  <feature name="org.gnu.gdb.z80.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="data_ptr"/>
+    <reg name="de" bitsize="16" type="data_ptr"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+enum Packet {
+    Interrupt,
+    Data(String),
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    breakpoints: Vec<Breakpoint>,
+    stopped: bool,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len() / 2).map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()).collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+fn get_register(gb: &Gameboy, n: usize) -> Option<u16> {
+    let r = gb.cpu.registers();
+    match n {
+        0 => Some((r.a as u16) << 8 | r.f as u16),
+        1 => Some((r.b as u16) << 8 | r.c as u16),
+        2 => Some((r.d as u16) << 8 | r.e as u16),
+        3 => Some((r.h as u16) << 8 | r.l as u16),
+        4 => Some(r.sp),
+        5 => Some(r.pc),
+        _ => None,
+    }
+}
+
+fn set_register(gb: &mut Gameboy, n: usize, val: u16) -> bool {
+    let mut r = gb.cpu.registers();
+    let (hi, lo) = ((val >> 8) as u8, val as u8);
+    match n {
+        0 => { r.a = hi; r.f = lo; }
+        1 => { r.b = hi; r.c = lo; }
+        2 => { r.d = hi; r.e = lo; }
+        3 => { r.h = hi; r.l = lo; }
+        4 => { r.sp = val; }
+        5 => { r.pc = val; }
+        _ => return false,
+    }
+    gb.cpu.set_registers(&r);
+    true
+}
+
+impl GdbStub {
+    /// Waits for gdb to connect. The target starts out stopped.
+    pub fn accept(listener: &TcpListener) -> Result<GdbStub, io::Error> {
+        let (stream, _) = try!(listener.accept());
+        try!(stream.set_nodelay(true));
+        Ok(GdbStub {
+            stream: stream,
+            buf: Vec::new(),
+            breakpoints: Vec::new(),
+            stopped: true,
+        })
+    }
+
+    fn send(&mut self, data: &str) -> Result<(), io::Error> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    // Takes the next complete packet out of the buffer, acking it.
+    fn parse_packet(&mut self) -> Result<Option<Packet>, io::Error> {
+        loop {
+            match self.buf.first().cloned() {
+                None => return Ok(None),
+                Some(0x03) => {
+                    self.buf.remove(0);
+                    return Ok(Some(Packet::Interrupt));
+                }
+                Some(b'$') => break,
+                // acks from gdb and noise
+                Some(_) => { self.buf.remove(0); }
+            }
+        }
+        let end = match self.buf.iter().position(|b| *b == b'#') {
+            Some(end) if end + 2 < self.buf.len() => end,
+            _ => return Ok(None),
+        };
+        let packet : Vec<u8> = self.buf.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let sum = String::from_utf8_lossy(&packet[end + 1..]).into_owned();
+        if parse_hex(&sum) != Some(checksum(data) as u32) {
+            try!(self.stream.write_all(b"-"));
+            return Ok(None);
+        }
+        try!(self.stream.write_all(b"+"));
+        Ok(Some(Packet::Data(String::from_utf8_lossy(data).into_owned())))
+    }
+
+    // Returns None if nothing is available without blocking. A closed
+    // connection is reported as an error.
+    fn read_packet(&mut self, block: bool) -> Result<Option<Packet>, io::Error> {
+        try!(self.stream.set_nonblocking(!block));
+        loop {
+            if let Some(packet) = try!(self.parse_packet()) {
+                return Ok(Some(packet));
+            }
+            let mut buf = [0; 1024];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "gdb disconnected")),
+                Ok(n) => self.buf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn stop(&mut self, signal: u8) -> Result<(), io::Error> {
+        self.stopped = true;
+        self.send(&format!("S{:02x}", signal))
+    }
+
+
+    // Handles one packet. Returns false when gdb detaches.
+    fn handle(&mut self, gb: &mut Gameboy, packet: &str) -> Result<bool, io::Error> {
+        let (cmd, args) = packet.split_at(::std::cmp::min(1, packet.len()));
+        match cmd {
+            "?" => try!(self.send(&format!("S{:02x}", SIGTRAP))),
+            "g" => {
+                let mut regs = Vec::new();
+                for n in 0..6 {
+                    let val = get_register(gb, n).unwrap();
+                    regs.push(val as u8);
+                    regs.push((val >> 8) as u8);
+                }
+                try!(self.send(&to_hex(&regs)));
+            }
+            "G" => {
+                match from_hex(args) {
+                    Some(ref regs) if regs.len() == 12 => {
+                        for n in 0..6 {
+                            set_register(gb, n, regs[n * 2] as u16 | (regs[n * 2 + 1] as u16) << 8);
+                        }
+                        try!(self.send("OK"));
+                    }
+                    _ => try!(self.send("E01")),
+                }
+            }
+            "p" => {
+                match parse_hex(args).and_then(|n| get_register(gb, n as usize)) {
+                    Some(val) => try!(self.send(&to_hex(&[val as u8, (val >> 8) as u8]))),
+                    None => try!(self.send("E01")),
+                }
+            }
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let n = parts.next().and_then(parse_hex);
+                let val = parts.next().and_then(from_hex);
+                match (n, val) {
+                    (Some(n), Some(ref val)) if val.len() == 2 &&
+                        set_register(gb, n as usize, val[0] as u16 | (val[1] as u16) << 8) => {
+                        try!(self.send("OK"));
+                    }
+                    _ => try!(self.send("E01")),
+                }
+            }
+            "m" => {
+                let mut parts = args.splitn(2, ',');
+                match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+                    (Some(addr), Some(len)) if (len as usize) <= PACKET_SIZE / 2 => {
+                        let bytes : Vec<u8> = (0..len).map(|i| gb.mm.read((addr as u16).wrapping_add(i as u16))).collect();
+                        try!(self.send(&to_hex(&bytes)));
+                    }
+                    _ => try!(self.send("E01")),
+                }
+            }
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let mut range = parts.next().unwrap_or("").splitn(2, ',');
+                let addr = range.next().and_then(parse_hex);
+                let len = range.next().and_then(parse_hex);
+                match (addr, len, parts.next().and_then(from_hex)) {
+                    (Some(addr), Some(len), Some(ref bytes)) if bytes.len() == len as usize => {
+                        for (i, b) in bytes.iter().enumerate() {
+                            gb.mm.write((addr as u16).wrapping_add(i as u16), *b);
+                        }
+                        try!(self.send("OK"));
+                    }
+                    _ => try!(self.send("E01")),
+                }
+            }
+            "c" => {
+                if let Some(pc) = parse_hex(args) {
+                    set_register(gb, 5, pc as u16);
+                }
+                self.stopped = false;
+            }
+            "s" => {
+                if let Some(pc) = parse_hex(args) {
+                    set_register(gb, 5, pc as u16);
+                }
+                gb.step();
+                let signal = if gb.lockup_event().is_some() { SIGILL } else { SIGTRAP };
+                try!(self.stop(signal));
+            }
+            "Z" | "z" => {
+                let parts : Vec<&str> = args.split(',').collect();
+                match (parts.get(0).map(|s| *s), parts.get(1).and_then(|s| parse_hex(s))) {
+                    (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                        let addr = addr as u16;
+                        if cmd == "Z" {
+                            let b = Breakpoint { bank: symbols::bank_at(&gb.mm, addr), addr: addr };
+                            if !self.breakpoints.contains(&b) {
+                                self.breakpoints.push(b);
+                            }
+                        } else {
+                            self.breakpoints.retain(|b| b.addr != addr);
+                        }
+                        try!(self.send("OK"));
+                    }
+                    _ => try!(self.send("")),
+                }
+            }
+            "q" if args.starts_with("Supported") => {
+                try!(self.send(&format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)));
+            }
+            "q" if args.starts_with("Xfer:features:read:") => {
+                // qXfer:features:read:ANNEX:OFFSET,LENGTH, "m" if there's more
+                let mut parts = args["Xfer:features:read:".len()..].splitn(2, ':');
+                let annex = parts.next();
+                let mut range = parts.next().unwrap_or("").splitn(2, ',');
+                match (annex, range.next().and_then(parse_hex), range.next().and_then(parse_hex)) {
+                    (Some("target.xml"), Some(offset), Some(len)) => {
+                        let start = ::std::cmp::min(offset as usize, TARGET_XML.len());
+                        let len = ::std::cmp::min(len as usize, PACKET_SIZE - 1);
+                        let end = ::std::cmp::min(start + len, TARGET_XML.len());
+                        let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                        try!(self.send(&format!("{}{}", more, &TARGET_XML[start..end])));
+                    }
+                    _ => try!(self.send("E00")),
+                }
+            }
+            "q" if args == "Attached" => try!(self.send("1")),
+            "H" => try!(self.send("OK")),
+            "D" => {
+                try!(self.send("OK"));
+                return Ok(false);
+            }
+            "k" => return Ok(false),
+            _ => try!(self.send("")),
+        }
+        Ok(true)
+    }
+
+    /// Runs one frame while gdb has the target running, otherwise serves gdb
+    /// until it continues. Returns false once gdb detaches or kills the
+    /// session, the game keeps running without it from then on.
+    pub fn run_frame(&mut self, gb: &mut Gameboy) -> Result<bool, io::Error> {
+        while self.stopped {
+            match try!(self.read_packet(true)) {
+                Some(Packet::Data(packet)) => {
+                    if !try!(self.handle(gb, &packet)) {
+                        return Ok(false);
+                    }
+                }
+                Some(Packet::Interrupt) | None => {}
+            }
+        }
+
+        let start = gb.cycles();
+        loop {
+            let vblank = gb.step();
+            if gb.lockup_event().is_some() {
+                try!(self.stop(SIGILL));
+                return Ok(true);
+            }
+            let pc = gb.cpu.registers().pc;
+            if self.breakpoints.iter().any(|b| b.hit(&gb.mm, pc)) {
+                try!(self.stop(SIGTRAP));
+                return Ok(true);
+            }
+            if vblank || gb.cycles().wrapping_sub(start) >= gameboy::CYCLES_PER_FRAME {
+                break;
+            }
+        }
+        // ctrl-c from gdb
+        match try!(self.read_packet(false)) {
+            Some(Packet::Interrupt) => try!(self.stop(SIGINT)),
+            Some(Packet::Data(packet)) => {
+                if !try!(self.handle(gb, &packet)) {
+                    return Ok(false);
+                }
+            }
+            None => {}
+        }
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_gdb() {
+    use std::thread;
+    use std::time::Duration;
+
+    // a scripted gdb session, returns the replies it got
+    fn client(port: u16) -> Vec<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut replies = Vec::new();
+        for packet in &["?", "g", "m100,4", "Z0,105,1", "c", "p5", "s", "Mc000,2:1234", "mc000,2",
+                        "mffffffff,2", "m0,ffffffff", "Mffffffff,2:0000", "Z0,4000,1", "c", "p5",
+                        "m4000,2", "qSupported:xmlRegisters=i386", "qXfer:features:read:target.xml:0,40",
+                        "qXfer:features:read:target.xml:40,1000", "qXfer:features:read:foo.xml:0,10", "D"] {
+            let data = format!("${}#{:02x}", packet, checksum(packet.as_bytes()));
+            stream.write_all(data.as_bytes()).unwrap();
+            // read the ack and one full reply
+            let mut reply = Vec::new();
+            let mut hashes = 0;
+            while hashes == 0 || reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+                let mut b = [0];
+                stream.read_exact(&mut b).unwrap();
+                if b[0] == b'#' { hashes += 1; }
+                reply.push(b[0]);
+            }
+            let reply = String::from_utf8(reply).unwrap();
+            let start = reply.find('$').unwrap();
+            let end = reply.find('#').unwrap();
+            replies.push(reply[start + 1..end].to_string());
+        }
+        replies
+    }
+
+    let mut rom = vec![0; 0x10000];
+    rom[0x147] = 0x01; // mbc1, 4 banks
+    rom[0x148] = 0x01;
+    // ld bc, $1234; inc b; inc b; inc b; ld a, 2; ld ($2000), a; jp $4000
+    rom[0x100..0x10e].copy_from_slice(&[0x01, 0x34, 0x12, 0x04, 0x04, 0x04,
+                                        0x3e, 0x02, 0xea, 0x00, 0x20, 0xc3, 0x00, 0x40]);
+    // bank 2: ld a, 1; ld ($2000), a
+    rom[0x8000..0x8005].copy_from_slice(&[0x3e, 0x01, 0xea, 0x00, 0x20]);
+    // bank 1: jr -2, and jp $4000 right after the switch in bank 2
+    rom[0x4000..0x4002].copy_from_slice(&[0x18, 0xfe]);
+    rom[0x4005..0x4008].copy_from_slice(&[0xc3, 0x00, 0x40]);
+    let mut gb = Gameboy::new(rom).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let client = thread::spawn(move || client(port));
+
+    let mut stub = GdbStub::accept(&listener).unwrap();
+    while stub.run_frame(&mut gb).unwrap() {}
+
+    let replies = client.join().unwrap();
+    assert_eq!(&replies[..17], &[
+        "S05",
+        "b0011300d8004d01feff0001", // af bc de hl sp pc
+        "01341204",
+        "OK",
+        "S05",      // breakpoint at 0x105
+        "0501",
+        "S05",
+        "OK",
+        "1234",
+        "0000",     // wraps around to 0x0000
+        "E01",      // longer than PacketSize
+        "OK",
+        "OK",
+        "S05",      // breakpoint at 0x4000 in bank 1, passing it in bank 2
+        "0040",
+        "18fe",
+        "PacketSize=1000;qXfer:features:read+",
+    ]);
+    // the target description comes in two pieces
+    assert!(replies[17].starts_with('m') && replies[18].starts_with('l'));
+    let xml = format!("{}{}", &replies[17][1..], &replies[18][1..]);
+    assert_eq!(xml, TARGET_XML);
+    assert!(xml.contains("<architecture>z80</architecture>"));
+    let attr = |s: &str, name: &str| {
+        let start = s.find(&format!("{}=\"", name)).unwrap() + name.len() + 2;
+        s[start..start + s[start..].find('"').unwrap()].to_string()
+    };
+    let regs : Vec<(String, String, String)> = xml.split("<reg ").skip(1)
+        .map(|r| (attr(r, "name"), attr(r, "bitsize"), attr(r, "type"))).collect();
+    let names : Vec<&str> = regs.iter().map(|r| &r.0[..]).collect();
+    assert_eq!(names, ["af", "bc", "de", "hl", "sp", "pc"]);
+    assert!(regs.iter().all(|r| r.1 == "16"));
+    assert_eq!(regs[4].2, "data_ptr");
+    assert_eq!(regs[5].2, "code_ptr");
+    assert_eq!(&replies[19..], &["E00", "OK"]);
+    assert_eq!(gb.cpu.registers().pc, 0x4000);
+    assert_eq!(gb.cpu.registers().b, 0x15);
+}
@@ -0,0 +1,101 @@
+use std::io;
+use std::io::prelude::*;
+
+/// Expands an RGB332 framebuffer into packed RGB888.
+pub fn rgb332_to_rgb(pixels: &[u8]) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(pixels.len() * 3);
+    for p in pixels {
+        rgb.push((((p >> 5) & 0x7) as u32 * 255 / 7) as u8);
+        rgb.push((((p >> 2) & 0x7) as u32 * 255 / 7) as u8);
+        rgb.push(((p & 0x3) as u32 * 255 / 3) as u8);
+    }
+    rgb
+}
+
+pub fn write_ppm<W: Write>(w: &mut W, width: usize, height: usize, rgb: &[u8]) -> Result<(), io::Error> {
+    try!(write!(w, "P6\n{} {}\n255\n", width, height));
+    try!(w.write_all(rgb));
+    Ok(())
+}
+
+fn crc32(crc: u32, buf: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in buf {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 > 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(buf: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for x in buf {
+        a = (a + *x as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+fn push_u32(v: &mut Vec<u8>, val: u32) {
+    v.push((val >> 24) as u8);
+    v.push((val >> 16) as u8);
+    v.push((val >> 8) as u8);
+    v.push(val as u8);
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8], data: &[u8]) -> Result<(), io::Error> {
+    let mut buf = Vec::with_capacity(data.len() + 12);
+    push_u32(&mut buf, data.len() as u32);
+    buf.extend_from_slice(kind);
+    buf.extend_from_slice(data);
+    let crc = crc32(0, &buf[4..]);
+    push_u32(&mut buf, crc);
+    w.write_all(&buf)
+}
+
+/// Writes an 8-bit RGB png. The image data is stored uncompressed, which
+/// keeps this small and is plenty for 160 pixel wide screens.
+pub fn write_png<W: Write>(w: &mut W, width: usize, height: usize, rgb: &[u8]) -> Result<(), io::Error> {
+    try!(w.write_all(b"\x89PNG\r\n\x1a\n"));
+
+    let mut ihdr = Vec::new();
+    push_u32(&mut ihdr, width as u32);
+    push_u32(&mut ihdr, height as u32);
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit, rgb, deflate, no filter, no interlace
+    try!(write_chunk(w, b"IHDR", &ihdr));
+
+    // every scanline is prefixed with filter type 0
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for line in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+
+    // zlib stream made of stored deflate blocks
+    let mut idat = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        idat.push(if last { 1 } else { 0 });
+        idat.push(len as u8);
+        idat.push((len >> 8) as u8);
+        idat.push(!len as u8);
+        idat.push((!len >> 8) as u8);
+        idat.extend_from_slice(block);
+    }
+    let adler = adler32(&raw);
+    push_u32(&mut idat, adler);
+    try!(write_chunk(w, b"IDAT", &idat));
+
+    write_chunk(w, b"IEND", &[])
+}
+
+#[test]
+fn test_image() {
+    assert_eq!(crc32(0, b"IEND"), 0xae426082);
+    assert_eq!(rgb332_to_rgb(&[0xff, 0x00]), vec![255, 255, 255, 0, 0, 0]);
+}
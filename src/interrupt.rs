@@ -3,3 +3,9 @@ pub const INTERRUPT_LCD_STAT : u8 = 1<<1;
 pub const INTERRUPT_TIMER    : u8 = 1<<2;
 pub const INTERRUPT_SERIAL   : u8 = 1<<3;
 pub const INTERRUPT_JOYPAD   : u8 = 1<<4;
+pub const INTERRUPT_MASK     : u8 = 0x1f;
+
+/// Address of the handler for an interrupt bit.
+pub fn vector(interrupt: u8) -> u16 {
+    0x40 + 8 * interrupt.trailing_zeros() as u16
+}
@@ -1,7 +1,20 @@
-use sdl2::keyboard::Keycode;
+use std::io;
 
 use mem;
 use interrupt;
+use state;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    B,
+    A,
+    Select,
+    Start,
+}
 
 #[derive(Debug)]
 pub struct Joypad {
@@ -45,6 +58,27 @@ impl Joypad {
         }
     }
 
+    pub fn save_state(&self, w: &mut state::Writer) {
+        w.u8(self.flags);
+        for b in &[self.up, self.down, self.left, self.right,
+                   self.b, self.a, self.select, self.start] {
+            w.bool(*b);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        self.flags = try!(r.u8());
+        self.up = try!(r.bool());
+        self.down = try!(r.bool());
+        self.left = try!(r.bool());
+        self.right = try!(r.bool());
+        self.b = try!(r.bool());
+        self.a = try!(r.bool());
+        self.select = try!(r.bool());
+        self.start = try!(r.bool());
+        Ok(())
+    }
+
     pub fn set_flags(&mut self) {
         //println!("{:?}", self);
         self.flags |= 0x0f;
@@ -63,33 +97,24 @@ impl Joypad {
         //println!("flags = {:02x}", self.flags);
     }
 
-    pub fn handle_input(&mut self, mm: &mut mem::MemoryMap, keycode: Keycode, pressed: bool) {
-        //println!("keycode={} pressed={}", keycode, pressed);
-
-        match keycode {
-            Keycode::Up => { self.up = pressed; }
-            Keycode::Down => { self.down = pressed; }
-            Keycode::Left => { self.left = pressed; }
-            Keycode::Right => { self.right = pressed; }
-            Keycode::Z => { self.b = pressed; }
-            Keycode::X => { self.a = pressed; }
-            Keycode::A => { self.select = pressed; }
-            Keycode::S => { self.start = pressed; }
-            Keycode::B => {
-                self.start = pressed;
-                self.b = pressed;
-                self.a = pressed;
-                self.select = pressed;
-            }
-            _ => {}
-        }
+    /// True if a pressed button pulls one of the selected input lines low.
+    /// This is what wakes the cpu up from stop.
+    pub fn input_low(&self) -> bool {
+        self.flags & 0x0f != 0x0f
+    }
+
+    pub fn handle_input(&mut self, mm: &mut mem::MemoryMap, button: Button, pressed: bool) {
+        //println!("button={:?} pressed={}", button, pressed);
 
-        if pressed {
-            match keycode {
-                Keycode::L => { mm.dump(0xc000, 8*32); }
-                Keycode::O => { mm.dump(0xfe00, 0xa0); }
-                _ => {}
-            }
+        match button {
+            Button::Up => { self.up = pressed; }
+            Button::Down => { self.down = pressed; }
+            Button::Left => { self.left = pressed; }
+            Button::Right => { self.right = pressed; }
+            Button::B => { self.b = pressed; }
+            Button::A => { self.a = pressed; }
+            Button::Select => { self.select = pressed; }
+            Button::Start => { self.start = pressed; }
         }
 
         self.set_flags();
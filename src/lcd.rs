@@ -1,7 +1,9 @@
 use std::fmt;
+use std::io;
 use cpu;
 use mem;
 use interrupt;
+use state;
 
 #[derive(Default)]
 pub struct Lcd {
@@ -18,6 +20,8 @@ pub struct Lcd {
 	pub obp1: u8, // Object Palette 1 Data (R/W) - Non CGB Mode Only
 	pub dma: u8,  // DMA Transfer and Start Address (W)
     cycles: u32,
+    window_line: u8, // window row drawn next, only advances on lines showing it
+    pixels: Vec<u8>, // RGB332, redrawn as the lcd runs
 }
 
 const LCD_CTL_ENABLE                         : u8 = 1<<7; // (0=Off, 1=On)
@@ -45,6 +49,15 @@ const OAM_Y_FLIP             : u8 = 1<<6;
 const OAM_X_FLIP             : u8 = 1<<5;
 const OAM_PALETTE_NUMBER     : u8 = 1<<4;
 
+fn vram(mm: &mem::MemoryMap, addr: u16) -> u8 {
+    mm.vram[(addr - 0x8000) as usize]
+}
+
+// Shade (0-3) of a color index through a palette register.
+fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 3
+}
+
 impl fmt::Debug for Lcd {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Lcd {{ \
@@ -61,68 +74,40 @@ impl fmt::Debug for Lcd {
 
 impl Lcd {
     pub fn new() -> Lcd {
-        let lcd: Lcd = Default::default();
+        let mut lcd: Lcd = Default::default();
+        lcd.pixels = vec![255; 160*144];
         return lcd;
     }
 
-    fn interrupt_enabled(&self, int: u8, mm: &mem::MemoryMap) -> bool {
-        self.stat & int > 0
-    }
-
-    fn put_pixel(&self,
-                 mm: &mut mem::MemoryMap,
-                 pixels: &mut [u8; 160*144], x: i32, y: i32,
-                 color: u8, oam: bool) {
-        if x < 0 || y < 0 || y >= 144 || x >= 160 {
-            return;
-        }
-        if y != self.ly as i32 {
-            return;
+    pub fn save_state(&self, w: &mut state::Writer) {
+        for r in &[self.ctl, self.stat, self.scy, self.scx, self.ly, self.lyc,
+                   self.wy, self.wx, self.bgp, self.obp0, self.obp1, self.dma] {
+            w.u8(*r);
         }
-        pixels[y as usize * 160 + x as usize] = match color {
-            0 => { 0b111_111_11 }
-            1 => { 0b100_100_10 }
-            2 => { 0b010_010_01 }
-            3 => { 0b000_000_00 }
-            _ => { panic!("bad color {}", color); }
-        };
+        w.u32(self.cycles);
+        w.u8(self.window_line);
     }
 
-    fn draw_tile(&self,
-                 mm: &mut mem::MemoryMap,
-                 pixels: &mut [u8; 160*144], x: i32, y: i32,
-                 tile_start_addr: u16,
-                 palette: [u8; 4], oam_flags: u8, oam: bool) {
-        for j in 0..8 {
-            let l = mm.read(j*2 + tile_start_addr);
-            let h = mm.read(j*2 + tile_start_addr + 1);
-            for k in 0..8 {
-                let p = (((h & (1<<k)) >> k) << 1) | ((l & (1<<k)) >> k);
-                let xpos = if (oam_flags & OAM_X_FLIP) > 0 { x + k as i32 } else { x + 7 - k as i32 };
-                let ypos = if (oam_flags & OAM_Y_FLIP) > 0 { y + 7 - j as i32 } else { y + j as i32 };
-                if p == 0 && oam {
-                    continue
-                }
-                self.put_pixel(mm, pixels, xpos, ypos, palette[p as usize], oam);
-            }
-        }
+    pub fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        self.ctl = try!(r.u8());
+        self.stat = try!(r.u8());
+        self.scy = try!(r.u8());
+        self.scx = try!(r.u8());
+        self.ly = try!(r.u8());
+        self.lyc = try!(r.u8());
+        self.wy = try!(r.u8());
+        self.wx = try!(r.u8());
+        self.bgp = try!(r.u8());
+        self.obp0 = try!(r.u8());
+        self.obp1 = try!(r.u8());
+        self.dma = try!(r.u8());
+        self.cycles = try!(r.u32());
+        self.window_line = try!(r.u8());
+        Ok(())
     }
 
-    pub fn draw_tiles(&self, mm: &mut mem::MemoryMap, pixels: &mut [u8; 160*144]) {
-        let palette : [u8; 4] = [
-            (self.obp0 & 0x03),
-            (self.obp0 & 0x0c) >> 2,
-            (self.obp0 & 0x30) >> 4,
-            (self.obp0 & 0xc0) >> 6,
-            ];
-
-        let mut tile_start_addr = 0x8000;
-        for j in 0..12 {
-            for i in 0..16 {
-                self.draw_tile(mm, pixels, i as i32 * 8, j as i32 * 8, tile_start_addr, palette, 0, false);
-                tile_start_addr += 16;
-            }
-        }
+    fn interrupt_enabled(&self, int: u8, mm: &mem::MemoryMap) -> bool {
+        self.stat & int > 0
     }
 
     fn get_tile_map_addr(&self) -> u16 {
@@ -149,131 +134,135 @@ impl Lcd {
         }
     }
 
-    fn draw_bg(&self, mm: &mut mem::MemoryMap, pixels: &mut [u8; 160*144]) {
-        let palette : [u8; 4] = [
-            (self.bgp & 0x03),
-            (self.bgp & 0x0c) >> 2,
-            (self.bgp & 0x30) >> 4,
-            (self.bgp & 0xc0) >> 6,
-            ];
-
-        //println!("ly={} palette={:?}", self.ly, palette);
-
-        //println!("ctl={:02x} scx={} scy={}", self.ctl, self.scx, self.scy);
-        if self.ctl & LCD_CTL_BG_DISPLAY == 0 {
-            return;
-        }
+    // Color index of pixel x (0 = leftmost) in the tile row at addr.
+    fn tile_pixel(mm: &mem::MemoryMap, addr: u16, x: u8) -> u8 {
+        let l = vram(mm, addr);
+        let h = vram(mm, addr + 1);
+        let bit = 7 - x;
+        ((h >> bit) & 1) << 1 | ((l >> bit) & 1)
+    }
 
-        let tile_map_addr = self.get_tile_map_addr();
-        //println!("ly={} palette={:?} addr={:04x} tile_start={:04x}", self.ly, palette, tile_map_addr, self.get_tile_start_addr(0));
-
-        for j in 0..19 {
-            for i in 0..21 {
-                let tile_pos_x = ((i + self.scx / 8) % 32) as u16;
-                let tile_pos_y = ((j + self.scy / 8) % 32) as u16;
-                let myaddr = tile_map_addr + tile_pos_y * 32 + tile_pos_x;
-                let tile = mm.read(myaddr);
-                let tile_start_addr = self.get_tile_start_addr(tile);
-                let x = i as i32 * 8 - (self.scx % 8) as i32;
-                let y = j as i32 * 8 - (self.scy % 8) as i32;
-                if self.ly as i32 >= y && self.ly as i32 <= y + 8 {
-                        //println!("tile_pos_x={:02x} tile_pos_y={:02x} tile={:02x} tile_start_addr={:04x} x={} y={} myaddr={:04x}",
-                        //         tile_pos_x, tile_pos_y, tile, tile_start_addr, x, y, myaddr);
-                    self.draw_tile(mm, pixels, x as i32, y as i32, tile_start_addr, palette, 0, false);
-                }
+    // Fills `colors` with the color indexes of the background, or the
+    // window from `start` on, for one line. The tile map is addressed with
+    // (x, y) in the 256x256 map.
+    fn fetch_bg_line(&self, mm: &mem::MemoryMap, colors: &mut [u8; 160], start: usize,
+                     map_addr: u16, x: u8, y: u8) {
+        let map_row = map_addr + (y as u16 / 8) * 32;
+        let mut x = x;
+        let mut row_addr = 0;
+        for i in start..160 {
+            // fetch the tile row at the start of each tile
+            if i == start || x % 8 == 0 {
+                let tile = vram(mm, map_row + x as u16 / 8);
+                row_addr = self.get_tile_start_addr(tile) + (y as u16 % 8) * 2;
             }
+            colors[i] = Lcd::tile_pixel(mm, row_addr, x % 8);
+            x = x.wrapping_add(1);
         }
     }
 
-    fn draw_window(&self, mm: &mut mem::MemoryMap, pixels: &mut [u8; 160*144]) {
-        if (self.ctl & LCD_CTL_WINDOW_DISPLAY_ENABLE) == 0 {
+    // Up to 10 sprites on the current line, in drawing priority order:
+    // smaller x first and the oam index for equal x.
+    fn select_sprites(&self, mm: &mem::MemoryMap) -> Vec<usize> {
+        let height = if (self.ctl & LCD_CTL_OBJ_SIZE) > 0 { 16 } else { 8 };
+        let line = self.ly as i32 + 16;
+        let mut sprites : Vec<usize> = (0..40).filter(|i| {
+            let y = mm.oam[i * 4] as i32;
+            line >= y && line < y + height
+        }).take(10).collect();
+        sprites.sort_by_key(|i| (mm.oam[i * 4 + 1], *i));
+        sprites
+    }
+
+    /// Renders line `ly` into the screen. The background/window color
+    /// indexes are kept for the line so sprites with the priority flag only
+    /// show over color 0. Where sprites overlap, the first one in priority
+    /// order with a non transparent pixel decides, even if it ends up hidden
+    /// behind the background.
+    fn render_line(&mut self, mm: &mem::MemoryMap) {
+        if (self.ctl & LCD_CTL_ENABLE) == 0 || self.ly >= 144 {
             return;
         }
+        if self.ly == 0 {
+            self.window_line = 0;
+        }
 
-        let palette : [u8; 4] = [
-            (self.bgp & 0x03),
-            (self.bgp & 0x0c) >> 2,
-            (self.bgp & 0x30) >> 4,
-            (self.bgp & 0xc0) >> 6,
-            ];
-
-        let tile_map_addr = self.get_window_tile_map_addr();
-
-        for j in 0..19 {
-            for i in 0..21 {
-                let tile_pos_x = (i % 32) as u16;
-                let tile_pos_y = (j % 32) as u16;
-                let myaddr = tile_map_addr + tile_pos_y * 32 + tile_pos_x;
-                let tile = mm.read(myaddr);
-                let tile_start_addr = self.get_tile_start_addr(tile);
-                let x = i as i32 * 8 + self.wx as i32 - 6;
-                let y = j as i32 * 8 + self.wy as i32;
-                if self.ly as i32 >= y && self.ly as i32 <= y + 8 {
-                    self.draw_tile(mm, pixels, x as i32, y as i32, tile_start_addr, palette, 0, false);
-                }
+        // with the background off the line is white whatever bgp says, and
+        // bg stays color 0 for the sprite priority check
+        let mut bg = [0u8; 160];
+        let mut line = [0u8; 160];
+        if (self.ctl & LCD_CTL_BG_DISPLAY) > 0 {
+            let map_addr = self.get_tile_map_addr();
+            self.fetch_bg_line(mm, &mut bg, 0, map_addr, self.scx, self.scy.wrapping_add(self.ly));
+
+            // on the dmg the window goes away with the background
+            if (self.ctl & LCD_CTL_WINDOW_DISPLAY_ENABLE) > 0 && self.ly >= self.wy && self.wx < 167 {
+                let start = if self.wx < 7 { 0 } else { self.wx as usize - 7 };
+                let map_addr = self.get_window_tile_map_addr();
+                let x = (start + 7 - self.wx as usize) as u8;
+                self.fetch_bg_line(mm, &mut bg, start, map_addr, x, self.window_line);
+                self.window_line = self.window_line.wrapping_add(1);
             }
-        }
-    }
 
-    fn draw_oam_tile(&self, mm: &mut mem::MemoryMap, pixels: &mut [u8; 160*144], x: u8, y: u8, tile: u8, flags: u8) {
-        let tile_start_addr = 0x8000 + tile as u16 * 16;
-        let obp = if flags & OAM_PALETTE_NUMBER > 0 {
-            self.obp1
-        } else {
-            self.obp0
-        };
-        let palette : [u8; 4] = [
-            (obp & 0x03),
-            (obp & 0x0c) >> 2,
-            (obp & 0x30) >> 4,
-            (obp & 0xc0) >> 6,
-        ];
-        if self.ly as i32 >= y as i32 - 16 && self.ly as i32 <= y as i32 - 8 {
-            self.draw_tile(mm, pixels, x as i32 - 8, y as i32 - 16, tile_start_addr, palette, flags, true);
+            for x in 0..160 {
+                line[x] = shade(self.bgp, bg[x]);
+            }
         }
-    }
-
-    fn draw_oam(&self, mm: &mut mem::MemoryMap, pixels: &mut [u8; 160*144]) {
-        let is_8x8 = (self.ctl & LCD_CTL_OBJ_SIZE) == 0;
 
-        for nn in 0..40 {
-            let i = 39 - nn;
-            let y     = mm.read(0xfe00 + i*4 + 0);
-            let x     = mm.read(0xfe00 + i*4 + 1);
-            let tile  = mm.read(0xfe00 + i*4 + 2);
-            let flags = mm.read(0xfe00 + i*4 + 3);
+        if (self.ctl & LCD_CTL_OBJ_DISPLAY_ENABLE) > 0 {
+            let height = if (self.ctl & LCD_CTL_OBJ_SIZE) > 0 { 16 } else { 8 };
+            let mut taken = [false; 160];
+            for i in self.select_sprites(mm) {
+                let y     = mm.oam[i*4 + 0] as i32;
+                let x     = mm.oam[i*4 + 1] as i32;
+                let tile  = mm.oam[i*4 + 2];
+                let flags = mm.oam[i*4 + 3];
 
-            if y >= 160 {
-                continue;
-            }
-
-            if is_8x8 {
-                self.draw_oam_tile(mm, pixels, x, y, tile, flags);
-            } else {
+                let mut row = self.ly as i32 + 16 - y;
                 if (flags & OAM_Y_FLIP) > 0 {
-                    self.draw_oam_tile(mm, pixels, x, y + 8, tile & 0xfe, flags);
-                    self.draw_oam_tile(mm, pixels, x, y,     tile | 0x01, flags);
-                } else {
-                    self.draw_oam_tile(mm, pixels, x, y,     tile & 0xfe, flags);
-                    self.draw_oam_tile(mm, pixels, x, y + 8, tile | 0x01, flags);
+                    row = height - 1 - row;
+                }
+                let tile = if height == 16 { tile & 0xfe } else { tile };
+                let row_addr = 0x8000 + tile as u16 * 16 + row as u16 * 2;
+                let obp = if (flags & OAM_PALETTE_NUMBER) > 0 { self.obp1 } else { self.obp0 };
+
+                for k in 0..8 {
+                    let sx = x - 8 + k;
+                    if sx < 0 || sx >= 160 || taken[sx as usize] {
+                        continue;
+                    }
+                    let px = if (flags & OAM_X_FLIP) > 0 { 7 - k } else { k };
+                    let color = Lcd::tile_pixel(mm, row_addr, px as u8);
+                    if color == 0 {
+                        continue;
+                    }
+                    taken[sx as usize] = true;
+                    if (flags & OAM_OBJ_TO_BG_PRIORITY) > 0 && bg[sx as usize] != 0 {
+                        continue;
+                    }
+                    line[sx as usize] = shade(obp, color);
                 }
             }
         }
-    }
 
-    pub fn draw(&self, mm: &mut mem::MemoryMap, pixels: &mut [u8; 160*144]) {
-        if (self.ctl & LCD_CTL_ENABLE) == 0 {
-            return;
+        let start = self.ly as usize * 160;
+        for (dst, s) in self.pixels[start..start + 160].iter_mut().zip(line.iter()) {
+            *dst = match *s {
+                0 => { 0b111_111_11 }
+                1 => { 0b100_100_10 }
+                2 => { 0b010_010_01 }
+                _ => { 0b000_000_00 }
+            };
         }
+    }
 
-        self.draw_bg(mm, pixels);
-        self.draw_window(mm, pixels);
-        self.draw_oam(mm, pixels);
+    /// The screen as of the last drawn scanline, one RGB332 byte per pixel.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
     }
 
-    pub fn run(&mut self, mm: &mut mem::MemoryMap, cycles: u32, pixels: &mut [u8; 160*144]) -> bool {
+    pub fn run(&mut self, mm: &mut mem::MemoryMap, cycles: u32) -> bool {
         //println!("{:?}", self);
-        let prev_ly = self.ly;
         let mut vblank = false;
         self.cycles += cycles;
         match self.stat & LCD_STATUS_MODE {
@@ -297,6 +286,8 @@ impl Lcd {
             3 => {
                 if self.cycles > 169 {
                     self.cycles -= 169;
+                    // the line is done, draw it with the registers as they are now
+                    self.render_line(mm);
                     self.stat &= !3;
                     self.ly = self.ly.wrapping_add(1);
                     if self.interrupt_enabled(LCD_STATUS_LY_COINCIDENCE_INTERRUPT, mm) && self.ly == self.lyc {
@@ -307,9 +298,7 @@ impl Lcd {
                         if self.interrupt_enabled(LCD_STATUS_MODE_1_VBLANK_INTERRUPT, mm) {
                             mm.interrupt_flag |= interrupt::INTERRUPT_LCD_STAT;
                         }
-                        if mm.interrupt_master_enable {
-                            mm.interrupt_flag |= interrupt::INTERRUPT_VBLANK;
-                        }
+                        mm.interrupt_flag |= interrupt::INTERRUPT_VBLANK;
                         self.stat |= 1;
                     } else {
                         if self.interrupt_enabled(LCD_STATUS_MODE_0_HBLANK_INTERRUPT, mm) {
@@ -338,10 +327,6 @@ impl Lcd {
             },
         }
 
-        if prev_ly != self.ly {
-            // draw new scanline
-            self.draw(mm, pixels);
-        }
         return vblank;
     }
 }
@@ -350,4 +335,56 @@ impl Lcd {
 fn test_lcd() {
     let lcd = Lcd::new();
     assert_eq!(lcd.ctl, 0);
+
+    let mut mm = mem::MemoryMap::for_test(vec![0; 0x8000]);
+    // tile 1 is all color 3, tile 2 all color 1
+    for i in 0..16 {
+        mm.vram[16 + i] = 0xff;
+        mm.vram[32 + i] = if i % 2 == 0 { 0xff } else { 0 };
+    }
+    mm.vram[0x1800] = 1; // bg map, first tile
+    mm.vram[0x1c00] = 1; // window map, first tile
+    let sprites : &[(u8, u8, u8)] = &[
+        (8, 2, OAM_OBJ_TO_BG_PRIORITY),  // behind the black bg tile
+        (16, 2, OAM_OBJ_TO_BG_PRIORITY), // over bg color 0
+        (40, 2, 0),
+        (36, 1, 0),                      // later in oam but further left, wins
+        (0, 1, 0), (0, 1, 0), (0, 1, 0), // off screen, still count
+        (0, 1, 0), (0, 1, 0), (0, 1, 0),
+        (108, 1, 0),                     // 11th sprite on the line
+    ];
+    for (i, &(x, tile, flags)) in sprites.iter().enumerate() {
+        mm.oam[i * 4..i * 4 + 4].copy_from_slice(&[16, x, tile, flags]);
+    }
+
+    let mut lcd = Lcd::new();
+    lcd.ctl = LCD_CTL_ENABLE | LCD_CTL_BG_WINDOW_TILE_DATA_SELECT | LCD_CTL_BG_DISPLAY |
+              LCD_CTL_OBJ_DISPLAY_ENABLE | LCD_CTL_WINDOW_DISPLAY_ENABLE |
+              LCD_CTL_WINDOW_TILE_MAP_DISPLAY_SELECT;
+    lcd.bgp = 0xe4;
+    lcd.obp0 = 0xe4;
+    lcd.wx = 7 + 120;
+    lcd.render_line(&mm);
+
+    let black = 0b000_000_00;
+    let light = 0b100_100_10;
+    let white = 0b111_111_11;
+    let line = &lcd.pixels()[..160];
+    assert_eq!(&line[0..8], &[black; 8]);
+    assert_eq!(&line[8..16], &[light; 8]);
+    assert_eq!(&line[28..36], &[black; 8]);
+    assert_eq!(&line[36..40], &[light; 4]);
+    assert_eq!(line[100], white);
+    assert_eq!(&line[120..128], &[black; 8]);
+    assert_eq!(line[128], white);
+    assert_eq!(lcd.window_line, 1);
+
+    // background off is white even when bgp maps color 0 to black
+    lcd.ctl &= !LCD_CTL_BG_DISPLAY;
+    lcd.bgp = 0xe7;
+    lcd.render_line(&mm);
+    let line = &lcd.pixels()[..160];
+    assert_eq!(&line[0..8], &[light; 8]); // the bg priority sprite shows now
+    assert_eq!(line[100], white);
+    assert_eq!(line[125], white);
 }
@@ -0,0 +1,33 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
+#![allow(unused_variables)]
+
+#[macro_use] extern crate log;
+
+pub mod cpu;
+pub mod lcd;
+pub mod timer;
+pub mod interrupt;
+pub mod mem;
+pub mod joypad;
+pub mod serial;
+pub mod link;
+pub mod printer;
+pub mod sound;
+pub mod cartridge;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod rtc;
+pub mod gameboy;
+pub mod image;
+pub mod state;
+pub mod disasm;
+pub mod trace;
+pub mod symbols;
+pub mod debugger;
+pub mod gdb;
+
+pub use gameboy::Gameboy;
+pub use joypad::Button;
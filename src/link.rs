@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use serial::{self, SerialDevice};
+
+// Link cable between two emulators over a socket, run in lockstep on
+// emulated time. Both sides count cycles from when the cable is plugged in
+// and stamp every message with their count:
+//
+// SYNC   tells the other side how far we got, sent every SYNC_CYCLES.
+// START  we drive the clock, the transfer ends CYCLES_PER_BYTE from now.
+// STOP   the transfer we started was called off.
+// XFER   our started transfer ended, carries our byte.
+// REPLY  the other side's answer to an XFER.
+//
+// Neither side may run more than CYCLES_PER_BYTE ahead of the last time it
+// heard from the other, so a START always arrives before its transfer ends.
+// When the other side's transfer ends we wait for its XFER and answer with
+// our byte if we have a transfer started on the external clock, otherwise
+// with 0xff. Whether a byte gets through therefore only depends on emulated
+// time, not on how fast either emulator runs. If both sides drive the clock
+// and their transfers end together, each answers the other's XFER with 0xff.
+//
+// A wait that hears nothing for TIMEOUT counts as the cable being pulled:
+// the connection is dropped and transfers read 0xff from then on.
+
+const SYNC  : u8 = 1;
+const START : u8 = 2;
+const STOP  : u8 = 3;
+const XFER  : u8 = 4;
+const REPLY : u8 = 5;
+
+// kind, time (u64 little endian), byte
+const MSG_LEN : usize = 10;
+
+const SYNC_CYCLES : u64 = 1024;
+const LOOKAHEAD : u64 = serial::CYCLES_PER_BYTE as u64;
+
+const TIMEOUT_MS : u64 = 2000;
+
+/// A socket a link cable can run over.
+pub trait LinkStream: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error>;
+}
+
+impl LinkStream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl LinkStream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+pub struct LinkCable<S: LinkStream> {
+    stream: Option<S>, // None once the cable is pulled
+    buf: Vec<u8>,
+    time: u64, // cycles since the cable was plugged in
+    next_sync: u64,
+    peer_time: u64, // the other side got at least this far
+    peer_ends: VecDeque<(u64, Option<u8>)>, // other side's transfers: end time, its byte once XFER came
+    end: Option<u64>, // when our own transfer ends
+    reply: Option<u8>,
+}
+
+impl<S: LinkStream> LinkCable<S> {
+    pub fn new(stream: S) -> Result<LinkCable<S>, io::Error> {
+        try!(stream.set_read_timeout(Some(Duration::from_millis(TIMEOUT_MS))));
+        Ok(LinkCable {
+            stream: Some(stream),
+            buf: Vec::new(),
+            time: 0,
+            next_sync: SYNC_CYCLES,
+            peer_time: 0,
+            peer_ends: VecDeque::new(),
+            end: None,
+            reply: None,
+        })
+    }
+
+    fn send(&mut self, kind: u8, val: u8) -> Result<(), io::Error> {
+        let mut msg = [0; MSG_LEN];
+        msg[0] = kind;
+        for i in 0..8 {
+            msg[1 + i] = (self.time >> (i * 8)) as u8;
+        }
+        msg[9] = val;
+        self.stream.as_mut().unwrap().write_all(&msg)
+    }
+
+    // Waits for the next message and takes it in.
+    fn recv(&mut self) -> Result<(), io::Error> {
+        while self.buf.len() < MSG_LEN {
+            let mut buf = [0; 64];
+            match self.stream.as_mut().unwrap().read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "link cable unplugged")),
+                Ok(n) => self.buf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "the other side stopped responding")),
+                Err(e) => return Err(e),
+            }
+        }
+        let msg : Vec<u8> = self.buf.drain(..MSG_LEN).collect();
+        let mut time = 0u64;
+        for i in 0..8 {
+            time |= (msg[1 + i] as u64) << (i * 8);
+        }
+        let val = msg[9];
+        if time > self.peer_time {
+            self.peer_time = time;
+        }
+        match msg[0] {
+            START => self.peer_ends.push_back((time + LOOKAHEAD, None)),
+            STOP => { self.peer_ends.pop_back(); }
+            XFER => {
+                match self.peer_ends.iter_mut().find(|e| e.1.is_none()) {
+                    Some(e) => e.1 = Some(val),
+                    // no START for it, e.g. after loading a save state
+                    None => try!(self.send(REPLY, 0xff)),
+                }
+            }
+            REPLY => self.reply = Some(val),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn try_tick(&mut self, cycles: u32, mut out: Option<u8>) -> Result<Option<u8>, io::Error> {
+        self.time += cycles as u64;
+        if self.time >= self.next_sync {
+            try!(self.send(SYNC, 0));
+            self.next_sync = self.time + SYNC_CYCLES;
+        }
+        let mut got = None;
+        loop {
+            // a transfer ending together with ours is answered in transfer
+            let due = match self.peer_ends.front() {
+                Some(&(end, val)) if end <= self.time && self.end.map_or(true, |e| end < e) => Some(val),
+                _ => None,
+            };
+            match due {
+                Some(Some(val)) => {
+                    self.peer_ends.pop_front();
+                    try!(self.send(REPLY, out.unwrap_or(0xff)));
+                    if out.take().is_some() {
+                        got = Some(val);
+                    }
+                }
+                Some(None) => try!(self.recv()),
+                None if self.time >= self.peer_time + LOOKAHEAD => try!(self.recv()),
+                None => return Ok(got),
+            }
+        }
+    }
+
+    fn try_set_clock(&mut self, driving: bool) -> Result<(), io::Error> {
+        if self.end.take().is_some() {
+            try!(self.send(STOP, 0));
+        }
+        if driving {
+            self.end = Some(self.time + LOOKAHEAD);
+            try!(self.send(START, 0));
+        }
+        Ok(())
+    }
+
+    fn try_transfer(&mut self, out: u8) -> Result<u8, io::Error> {
+        let end = self.end.take();
+        self.reply = None;
+        try!(self.send(XFER, out));
+        loop {
+            if let Some(val) = self.reply.take() {
+                return Ok(val);
+            }
+            match self.peer_ends.front() {
+                // both sides drive the clock
+                Some(&(e, Some(_))) if Some(e) == end => {
+                    self.peer_ends.pop_front();
+                    try!(self.send(REPLY, 0xff));
+                }
+                _ => try!(self.recv()),
+            }
+        }
+    }
+
+    fn unplug(&mut self, e: io::Error) {
+        println!("link cable: {}", e);
+        self.stream = None;
+    }
+}
+
+impl<S: LinkStream> SerialDevice for LinkCable<S> {
+    fn transfer(&mut self, out: u8) -> u8 {
+        if self.stream.is_none() {
+            return 0xff;
+        }
+        match self.try_transfer(out) {
+            Ok(val) => val,
+            Err(e) => { self.unplug(e); 0xff }
+        }
+    }
+
+    fn set_clock(&mut self, driving: bool) {
+        if self.stream.is_none() {
+            return;
+        }
+        if let Err(e) = self.try_set_clock(driving) {
+            self.unplug(e);
+        }
+    }
+
+    fn tick(&mut self, cycles: u32, out: Option<u8>) -> Option<u8> {
+        if self.stream.is_none() {
+            return None;
+        }
+        match self.try_tick(cycles, out) {
+            Ok(val) => val,
+            Err(e) => { self.unplug(e); None }
+        }
+    }
+}
+
+fn tcp(stream: TcpStream) -> Result<Box<SerialDevice>, io::Error> {
+    try!(stream.set_nodelay(true));
+    Ok(Box::new(try!(LinkCable::new(stream))))
+}
+
+#[cfg(unix)]
+fn listen_unix(path: &str) -> Result<Box<SerialDevice>, io::Error> {
+    let listener = try!(UnixListener::bind(path));
+    let (stream, _) = try!(listener.accept());
+    Ok(Box::new(try!(LinkCable::new(stream))))
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> Result<Box<SerialDevice>, io::Error> {
+    Ok(Box::new(try!(LinkCable::new(try!(UnixStream::connect(path))))))
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_: &str) -> Result<Box<SerialDevice>, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "unix sockets are not supported"))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_: &str) -> Result<Box<SerialDevice>, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "unix sockets are not supported"))
+}
+
+/// Waits for the other emulator to connect. `addr` is `HOST:PORT`, or
+/// `unix:PATH` for a unix socket.
+pub fn listen(addr: &str) -> Result<Box<SerialDevice>, io::Error> {
+    if addr.starts_with("unix:") {
+        return listen_unix(&addr[5..]);
+    }
+    let listener = try!(TcpListener::bind(addr));
+    let (stream, _) = try!(listener.accept());
+    tcp(stream)
+}
+
+/// Connects to an emulator waiting in `listen`.
+pub fn connect(addr: &str) -> Result<Box<SerialDevice>, io::Error> {
+    if addr.starts_with("unix:") {
+        return connect_unix(&addr[5..]);
+    }
+    tcp(try!(TcpStream::connect(addr)))
+}
+
+#[test]
+fn test_link() {
+    use std::thread;
+    use gameboy::Gameboy;
+
+    // Sends each of `bytes` with `sc`, storing the bytes it gets from 0xc000
+    // on. Waits about `delay` * 16 cycles before each transfer.
+    fn run(stream: TcpStream, sc: u8, delay: u8, bytes: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        let mut code = vec![0x21, 0x00, 0xc0];      // ld hl, $c000
+        for &b in bytes {
+            if delay > 0 {
+                code.extend_from_slice(&[0x06, delay, 0x05, 0x20, 0xfd]); // ld b, delay; dec b; jr nz, -3
+            }
+            code.extend_from_slice(&[
+                0x3e, b, 0xe0, 0x01,    // ld a, b; ldh ($01), a
+                0x3e, sc, 0xe0, 0x02,   // ld a, sc; ldh ($02), a
+                0xf0, 0x02, 0xcb, 0x7f, // ldh a, ($02); bit 7, a
+                0x20, 0xfa,             // jr nz, -6
+                0xf0, 0x01, 0x22,       // ldh a, ($01); ld (hl+), a
+            ]);
+        }
+        code.push(0x76);                            // halt
+        rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+        let mut gb = Gameboy::new(rom).unwrap();
+        gb.connect_serial(Box::new(LinkCable::new(stream).unwrap()));
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+        (0..bytes.len()).map(|i| gb.mm.read(0xc000 + i as u16)).collect()
+    }
+
+    // The slave side starts running a good while after the master, which
+    // has to wait for it.
+    fn link(master: (u8, u8, &'static [u8]), slave: (u8, u8, &'static [u8])) -> (Vec<u8>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let master = thread::spawn(move || run(TcpStream::connect(addr).unwrap(), master.0, master.1, master.2));
+        let stream = listener.accept().unwrap().0;
+        thread::sleep(Duration::from_millis(200));
+        let slave = run(stream, slave.0, slave.1, slave.2);
+        (master.join().unwrap(), slave)
+    }
+
+    // the slave arms each transfer well after the master started its own
+    assert_eq!(link((0x81, 0, &[0x10, 0x11, 0x12]), (0x80, 100, &[0x20, 0x21, 0x22])),
+               (vec![0x20, 0x21, 0x22], vec![0x10, 0x11, 0x12]));
+    // the other side never starts a transfer, so only the master gets a byte
+    assert_eq!(link((0x81, 0, &[0x42]), (0x00, 0, &[0x99])), (vec![0xff], vec![0x99]));
+    // both drive the clock at the same time
+    assert_eq!(link((0x81, 0, &[0x42]), (0x81, 0, &[0x99])), (vec![0xff], vec![0xff]));
+}
@@ -6,10 +6,15 @@
 extern crate env_logger;
 extern crate sdl2;
 extern crate time;
+extern crate rustboy;
 
 use std::io::prelude::*;
+use std::io;
 use std::fs::File;
+use std::io::BufWriter;
 use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::fmt;
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -18,121 +23,140 @@ use std::sync::RwLock;
 use std::thread;
 use std::vec;
 use std::time::Duration;
+use std::net::TcpListener;
 
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{LSHIFTMOD, RSHIFTMOD};
 use sdl2::render::Texture;
-use sdl2::audio::{AudioCallback, AudioSpecDesired};
-
-mod cpu;
-mod lcd;
-mod timer;
-mod interrupt;
-mod mem;
-mod joypad;
-mod sound;
-
-struct Gameboy {
-    cpu: cpu::Cpu,
-    mm: mem::MemoryMap,
-    lcd : Rc<RefCell<lcd::Lcd>>,
-    timer : Rc<RefCell<timer::Timer>>,
-    joypad : Rc<RefCell<joypad::Joypad>>,
-    sound : Arc<RwLock<sound::Sound>>,
+use sdl2::audio::{AudioCallback, AudioSpec, AudioSpecDesired};
+
+use rustboy::cartridge;
+use rustboy::joypad::Button;
+use rustboy::sound::SoundPlayer;
+use rustboy::state;
+use rustboy::debugger::{Debugger, Action};
+use rustboy::trace;
+use rustboy::gdb::GdbStub;
+use rustboy::link;
+use rustboy::printer::Printer;
+use rustboy::Gameboy;
+
+struct SdlSoundPlayer {
+    spec: AudioSpec,
+    player: SoundPlayer,
 }
 
-fn cart_type_str(val: u8) -> &'static str {
-	match val {
-		0x00 => "ROM ONLY",
-		0x01 => "MBC1",
-		0x02 => "MBC1+RAM",
-		0x03 => "MBC1+RAM+BATTERY",
-		0x05 => "MBC2",
-		0x06 => "MBC2+BATTERY",
-		0x08 => "ROM+RAM",
-		0x09 => "ROM+RAM+BATTERY",
-		0x0B => "MMM01",
-		0x0C => "MMM01+RAM",
-		0x0D => "MMM01+RAM+BATTERY",
-		0x0F => "MBC3+TIMER+BATTERY",
-		0x10 => "MBC3+TIMER+RAM+BATTERY",
-		0x11 => "MBC3",
-		0x12 => "MBC3+RAM",
-		0x13 => "MBC3+RAM+BATTERY",
-		0x15 => "MBC4",
-		0x16 => "MBC4+RAM",
-		0x17 => "MBC4+RAM+BATTERY",
-		0x19 => "MBC5",
-		0x1A => "MBC5+RAM",
-		0x1B => "MBC5+RAM+BATTERY",
-		0x1C => "MBC5+RUMBLE",
-		0x1D => "MBC5+RUMBLE+RAM",
-		0x1E => "MBC5+RUMBLE+RAM+BATTERY",
-		0xFC => "POCKET CAMERA",
-		0xFD => "BANDAI TAMA5",
-		0xFE => "HuC3",
-		0xFF => "HuC1+RAM+BATTERY",
-        _ => panic!("unknown cart type 0x{:02x}", val),
-	}
+impl AudioCallback for SdlSoundPlayer {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let n = self.spec.samples as usize;
+        self.player.fill(&mut out[..n]);
+    }
 }
 
-fn rom_size_str(val: u8) -> &'static str {
-    match val {
-        0x00 => "32KByte (no ROM banking)",
-        0x01 => "64KByte (4 banks)",
-        0x02 => "128KByte (8 banks)",
-        0x03 => "256KByte (16 banks)",
-        0x04 => "512KByte (32 banks)",
-        0x05 => "1MByte (64 banks)  - only 63 banks used by MBC1",
-        0x06 => "2MByte (128 banks) - only 125 banks used by MBC1",
-        0x07 => "4MByte (256 banks)",
-        0x52 => "1.1MByte (72 banks)",
-        0x53 => "1.2MByte (80 banks)",
-        0x54 => "1.5MByte (96 banks)",
-        _ => panic!("unknown rom size 0x{:02x}", val),
+fn keycode_to_button(keycode: Keycode) -> Option<Button> {
+    match keycode {
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Right => Some(Button::Right),
+        Keycode::Z => Some(Button::B),
+        Keycode::X => Some(Button::A),
+        Keycode::A => Some(Button::Select),
+        Keycode::S => Some(Button::Start),
+        _ => None,
     }
 }
 
-fn ram_size_str(val: u8) -> &'static str {
-    match val {
-        0x00 => "None",
-        0x01 => "2 KBytes",
-        0x02 => "8 KBytes",
-        0x03 => "32 KBytes (4 banks of 8 KBytes each)",
-        _ => panic!("unknown ram size 0x{:02x}", val),
+fn keycode_to_slot(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        Keycode::F5 => Some(5),
+        Keycode::F6 => Some(6),
+        Keycode::F7 => Some(7),
+        Keycode::F8 => Some(8),
+        Keycode::F9 => Some(9),
+        _ => None,
     }
 }
 
-fn print_rom_info(rom: &Vec<u8>) {
-    let title = &rom[0x134..0x143];
-    let mut s = String::new();
-    for c in title {
-        if *c == 0 {
-            break;
-        }
-        s.push(*c as char);
+fn save_state_slot(gb: &Gameboy, rom_path: &Path, slot: u8) -> Result<(), io::Error> {
+    let mut f = try!(File::create(state::state_path(rom_path, slot)));
+    f.write_all(&gb.save_state())
+}
+
+fn load_state_slot(gb: &mut Gameboy, rom_path: &Path, slot: u8) -> Result<(), io::Error> {
+    let mut buf = Vec::new();
+    let mut f = try!(File::open(state::state_path(rom_path, slot)));
+    try!(f.read_to_end(&mut buf));
+    gb.load_state(&buf)
+}
+
+fn usage() -> ! {
+    println!("usage: rustboy <rom> [--debug] [--gdb PORT]");
+    println!("              [--trace FILE [--trace-start PC] [--trace-stop PC] [--trace-labels]]");
+    println!("              [--link-listen ADDR | --link-connect ADDR | --printer DIR]");
+    println!("              [--serial-log FILE|stdout]");
+    process::exit(2);
+}
+
+fn open_serial_log(name: &str) -> Result<Box<Write>, io::Error> {
+    if name == "stdout" {
+        return Ok(Box::new(io::stdout()));
     }
-    println!("Title          = {}", s);
-    println!("CGB flag       = {:02x}", rom[0x143]);
-    println!("SGB flag       = {:02x}", rom[0x146]);
-    println!("Cartridge Type = {}", cart_type_str(rom[0x147]));
-    println!("ROM Size       = {}", rom_size_str(rom[0x148]));
-    println!("RAM Size       = {}", ram_size_str(rom[0x149]));
+    Ok(Box::new(BufWriter::new(try!(File::create(name)))))
+}
+
+fn parse_pc(gb: &Gameboy, arg: Option<String>) -> Option<(Option<usize>, u16)> {
+    arg.map(|s| gb.symbols.parse_pc(&s).unwrap_or_else(|| usage()))
 }
 
 fn main() {
     env_logger::init().unwrap();
 
-    let filename = env::args().nth(1).unwrap_or_else(|| panic!("must pass a rom"));
+    let mut args = env::args().skip(1);
+    let mut filename = None;
+    let mut trace_file = None;
+    let mut trace_start = None;
+    let mut trace_stop = None;
+    let mut trace_labels = false;
+    let mut debugger = Debugger::new();
+    let mut gdb_port = None;
+    let mut link_listen = None;
+    let mut link_connect = None;
+    let mut printer_dir = None;
+    let mut serial_log = None;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--debug" => { debugger.paused = true; }
+            "--gdb" => { gdb_port = Some(args.next().and_then(|s| s.parse::<u16>().ok()).unwrap_or_else(|| usage())); }
+            "--link-listen" => { link_listen = Some(args.next().unwrap_or_else(|| usage())); }
+            "--link-connect" => { link_connect = Some(args.next().unwrap_or_else(|| usage())); }
+            "--printer" => { printer_dir = Some(args.next().unwrap_or_else(|| usage())); }
+            "--serial-log" => { serial_log = Some(args.next().unwrap_or_else(|| usage())); }
+            "--trace" => { trace_file = Some(args.next().unwrap_or_else(|| usage())); }
+            "--trace-start" => { trace_start = Some(args.next().unwrap_or_else(|| usage())); }
+            "--trace-stop" => { trace_stop = Some(args.next().unwrap_or_else(|| usage())); }
+            "--trace-labels" => { trace_labels = true; }
+            "-h" | "--help" => { usage(); }
+            _ => { filename = Some(arg); }
+        }
+    }
+    let filename = filename.unwrap_or_else(|| usage());
     let mut f = File::open(&filename).unwrap();
     let mut rom = Vec::new();
     let size = f.read_to_end(&mut rom).unwrap();
 
     println!("filename = {} size = {:?}", filename, size);
 
-    print_rom_info(&rom);
+    cartridge::print_rom_info(&rom);
 
     let sdl_context = sdl2::init().unwrap();
 
@@ -147,44 +171,18 @@ fn main() {
         .unwrap();
     let mut renderer = window.renderer().build().unwrap();
     let mut texture = renderer.create_texture_streaming(PixelFormatEnum::RGB332, (160, 144)).unwrap();
-    let mut pixels: [u8; 160*144] = [255; 160*144];
     let pitch = 160;
-    texture.update(None, &pixels, pitch).unwrap();
     renderer.copy(&texture, None, None);
     renderer.present();
 
 
     // Initialize the emulator.
-    let cpu = cpu::Cpu::new();
-    let lcd = Rc::new(RefCell::new(lcd::Lcd::new()));
-    let timer = Rc::new(RefCell::new(timer::Timer::new()));
-    let joypad = Rc::new(RefCell::new(joypad::Joypad::new()));
-    let sound = Arc::new(RwLock::new(sound::Sound::new()));
-    let mm = mem::MemoryMap {
-        rom: rom,
-        vram: [0; 0x2000],
-        wram: [0; 0x2000],
-        hram: [0; 0x80],
-        eram: [0; 0x2000],
-        eram_enabled: false,
-        iobuf: [0; 0x100],
-        interrupt_enable: 0,
-        interrupt_master_enable: false,
-        interrupt_flag: 0,
-        oam: [0; 0xa0],
-        lcd: lcd.clone(),
-        timer: timer.clone(),
-        joypad: joypad.clone(),
-        sound: sound.clone(),
-        rom_bank: 1,
-    };
-    let mut gb = Gameboy {
-        cpu: cpu,
-        mm: mm,
-        lcd: lcd.clone(),
-        timer: timer.clone(),
-        joypad: joypad.clone(),
-        sound: sound.clone(),
+    let mut gb = match Gameboy::new(rom) {
+        Ok(gb) => gb,
+        Err(e) => {
+            println!("{}: {}", filename, e);
+            process::exit(1);
+        }
     };
 
 
@@ -198,85 +196,163 @@ fn main() {
     };
     let device = audio_subsystem.open_playback(None, desired_spec, |spec| {
         println!("spec = {:?}", spec);
-        sound::SoundPlayer {
+        SdlSoundPlayer {
             spec: spec,
-            volume: 0.05,
-            x: 5,
-            phase: 0.0,
-            phase2: 0.0,
-            phase3: 0.0,
-            phase4: 0.0,
-            sound: sound.clone(),
-            samples: vec![0; spec.samples as usize],
+            player: SoundPlayer::new(gb.sound.clone(), spec.freq),
         }
     }).unwrap();
     device.resume();
 
-    gb.mm.load_eram();
+    if let Err(e) = gb.load_battery(Path::new(&filename)) {
+        println!("error loading save: {}", e);
+    }
+    match gb.load_symbols(Path::new(&filename)) {
+        Ok(0) => {}
+        Ok(n) => println!("loaded {} symbols", n),
+        Err(e) => println!("error loading symbols: {}", e),
+    }
+    if let Some(trace_file) = trace_file {
+        let start = parse_pc(&gb, trace_start);
+        let stop = parse_pc(&gb, trace_stop);
+        match File::create(&trace_file) {
+            Ok(f) => {
+                let mut trace = trace::Trace::new(Box::new(BufWriter::new(f)), start, stop);
+                trace.set_labels(trace_labels);
+                gb.set_trace(trace);
+            }
+            Err(e) => println!("{}: {}", trace_file, e),
+        }
+    }
+    if let Some(addr) = link_listen {
+        println!("waiting for the other gameboy on {}", addr);
+        match link::listen(&addr) {
+            Ok(cable) => gb.connect_serial(cable),
+            Err(e) => println!("link cable: {}", e),
+        }
+    } else if let Some(addr) = link_connect {
+        match link::connect(&addr) {
+            Ok(cable) => gb.connect_serial(cable),
+            Err(e) => println!("link cable: {}", e),
+        }
+    } else if let Some(dir) = printer_dir {
+        gb.connect_serial(Box::new(Printer::new(PathBuf::from(dir))));
+    }
+    if let Some(name) = serial_log {
+        match open_serial_log(&name) {
+            Ok(out) => gb.set_serial_log(out),
+            Err(e) => println!("{}: {}", name, e),
+        }
+    }
+    let mut gdb = gdb_port.map(|port| {
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        println!("waiting for gdb on port {}", port);
+        GdbStub::accept(&listener).unwrap()
+    });
 
 
-    let mut prevcycles = 0u32;
     let mut start = time::now();
     let mut event_pump = sdl_context.event_pump().unwrap();
     let mut fastforward = false;
     'running: loop {
-        if prevcycles % 100000000 < 10 {
-            println!("cycles={}", prevcycles);
+        if debugger.paused {
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            match debugger.prompt(&mut gb, &mut stdin.lock(), &mut stdout.lock()) {
+                Ok(Action::Quit) => break 'running,
+                Ok(_) => {}
+                Err(e) => println!("debugger: {}", e),
+            }
+        }
+        if let Some(mut stub) = gdb.take() {
+            match stub.run_frame(&mut gb) {
+                Ok(true) => gdb = Some(stub),
+                Ok(false) => println!("gdb detached"),
+                Err(e) => println!("gdb: {}", e),
+            }
+        } else if let Some(reason) = debugger.run_frame(&mut gb) {
+            println!("{}", reason);
         }
 
-        let cycles = gb.cpu.run(&mut gb.mm);
-        let vblank = gb.lcd.borrow_mut().run(&mut gb.mm, cycles - prevcycles, &mut pixels);
-        gb.timer.borrow_mut().run(&mut gb.mm, cycles - prevcycles);
-        gb.sound.write().unwrap().run(&mut gb.mm, cycles - prevcycles);
-
-        if vblank {
-            for event in event_pump.poll_iter() {
-                match event {
-                    Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                        break 'running
-                    },
-                    Event::KeyDown { keycode: Some(Keycode::F), .. } => {
-                        fastforward = true;
-                    }
-                    Event::KeyUp { keycode: Some(Keycode::F), .. } => {
-                        fastforward = false;
+        if let Some(rumble) = gb.rumble_event() {
+            println!("rumble {}", if rumble { "on" } else { "off" });
+        }
+
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    break 'running
+                },
+                Event::KeyDown { keycode: Some(Keycode::F), .. } => {
+                    fastforward = true;
+                }
+                Event::KeyUp { keycode: Some(Keycode::F), .. } => {
+                    fastforward = false;
+                }
+                Event::KeyDown { keycode: Some(Keycode::D), .. } => {
+                    debugger.paused = true;
+                }
+                Event::KeyDown { keycode: Some(Keycode::L), .. } => {
+                    gb.mm.dump(0xc000, 8*32);
+                }
+                Event::KeyDown { keycode: Some(Keycode::O), .. } => {
+                    gb.mm.dump(0xfe00, 0xa0);
+                }
+                Event::KeyDown { keycode: Some(Keycode::B), .. } => {
+                    for button in &[Button::Start, Button::B, Button::A, Button::Select] {
+                        gb.press(*button);
                     }
-                    Event::KeyDown { keycode: Some(Keycode::D), .. } => {
-                        //gb.cpu.tracing = true;
-                        println!("{:?}", gb.lcd.borrow());
-                        gb.mm.dump(0x8000, 0xa000 - 0x8000);
-                        gb.mm.dump(0xfe00, 0x100);
-                        gb.mm.dump(0xff40, 0x20);
-                        panic!("asdf");
+                }
+                Event::KeyUp { keycode: Some(Keycode::B), .. } => {
+                    for button in &[Button::Start, Button::B, Button::A, Button::Select] {
+                        gb.release(*button);
                     }
-                    Event::KeyUp { keycode: Some(Keycode::D), .. } => {
-                        //gb.cpu.tracing = false;
+                }
+                // F1-F9 load a save state, shift+F1-F9 save one
+                Event::KeyDown { keycode: Some(keycode), keymod, .. } if keycode_to_slot(keycode).is_some() => {
+                    let slot = keycode_to_slot(keycode).unwrap();
+                    let rom_path = Path::new(&filename);
+                    if keymod.intersects(LSHIFTMOD | RSHIFTMOD) {
+                        match save_state_slot(&gb, rom_path, slot) {
+                            Ok(()) => println!("saved state {}", slot),
+                            Err(e) => println!("error saving state {}: {}", slot, e),
+                        }
+                    } else {
+                        match load_state_slot(&mut gb, rom_path, slot) {
+                            Ok(()) => println!("loaded state {}", slot),
+                            Err(e) => println!("error loading state {}: {}", slot, e),
+                        }
                     }
-                    Event::KeyDown { keycode: Some(keycode), .. } => {
-                        joypad.borrow_mut().handle_input(&mut gb.mm, keycode, true);
+                }
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    if let Some(button) = keycode_to_button(keycode) {
+                        gb.press(button);
                     }
-                    Event::KeyUp { keycode: Some(keycode), .. } => {
-                        joypad.borrow_mut().handle_input(&mut gb.mm, keycode, false);
+                }
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(button) = keycode_to_button(keycode) {
+                        gb.release(button);
                     }
-                    _ => {}
                 }
+                _ => {}
             }
+        }
 
-            //gb.lcd.borrow().draw(&mut gb.mm, &mut pixels);
-            texture.update(None, &pixels, pitch).unwrap();
-            renderer.copy(&texture, None, None);
-            renderer.present();
+        //gb.lcd.borrow().draw(&mut gb.mm, &mut pixels);
+        texture.update(None, gb.framebuffer(), pitch).unwrap();
+        renderer.copy(&texture, None, None);
+        renderer.present();
 
-            let end = time::now();
-            let delta = end - start;
-            start = end;
-            println!("ms={}", delta.num_milliseconds());
+        let end = time::now();
+        let delta = end - start;
+        start = end;
+        println!("ms={}", delta.num_milliseconds());
 
-            if !fastforward && delta.num_milliseconds() < 17 {
-                std::thread::sleep(Duration::from_millis(17 as u64 - delta.num_milliseconds() as u64));
-            }
+        if !fastforward && delta.num_milliseconds() < 17 {
+            std::thread::sleep(Duration::from_millis(17 as u64 - delta.num_milliseconds() as u64));
         }
+    }
 
-        prevcycles = cycles;
+    if let Err(e) = gb.save_battery() {
+        println!("error saving: {}", e);
     }
 }
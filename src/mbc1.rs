@@ -0,0 +1,186 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge;
+use cartridge::Mapper;
+use state;
+
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank1: u8, // lower 5 bits of the rom bank
+    bank2: u8, // ram bank or upper 2 bits of the rom bank
+    mode: u8,  // banking mode select (0=simple, 1=advanced)
+    mbc1m: bool, // multicart wiring, bank2 is shifted by 4 instead of 5
+}
+
+const NINTENDO_LOGO_ADDR : usize = 0x104;
+const NINTENDO_LOGO_LEN  : usize = 0x30;
+
+// MBC1 multicarts are 1 MiB roms made of four 256 KiB games, each with its
+// own header. Look for a second copy of the logo in the game at bank 0x10.
+fn is_mbc1m(rom: &Vec<u8>) -> bool {
+    if rom.len() != 0x100000 {
+        return false;
+    }
+    let logo = &rom[NINTENDO_LOGO_ADDR..NINTENDO_LOGO_ADDR + NINTENDO_LOGO_LEN];
+    let start = 0x10 * 0x4000 + NINTENDO_LOGO_ADDR;
+    &rom[start..start + NINTENDO_LOGO_LEN] == logo
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+        let mbc1m = is_mbc1m(&rom);
+        Mbc1 {
+            rom: rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: 0,
+            mbc1m: mbc1m,
+        }
+    }
+
+    fn upper_bank_shift(&self) -> usize {
+        if self.mbc1m { 4 } else { 5 }
+    }
+
+    // bank mapped at 0x0000-0x3fff, only changes in mode 1
+    fn rom_bank0(&self) -> usize {
+        if self.mode == 1 {
+            (self.bank2 as usize) << self.upper_bank_shift()
+        } else {
+            0
+        }
+    }
+
+    // bank mapped at 0x4000-0x7fff
+    fn rom_bankn(&self) -> usize {
+        let shift = self.upper_bank_shift();
+        let lower = self.bank1 as usize & ((1 << shift) - 1);
+        (self.bank2 as usize) << shift | lower
+    }
+
+    fn ram_addr(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.len() == 0 {
+            return None;
+        }
+        let bank = if self.mode == 1 { self.bank2 as usize } else { 0 };
+        Some(cartridge::ram_offset(&self.ram, bank, addr))
+    }
+}
+
+impl cartridge::Mapper for Mbc1 {
+    fn read_rom(&mut self, addr: u16) -> u8 {
+        match addr {
+            0 ... 0x3fff => cartridge::read_bank(&self.rom, self.rom_bank0(), addr),
+            _ => cartridge::read_bank(&self.rom, self.rom_bankn(), addr),
+        }
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0 ... 0x1fff => {
+                self.ram_enabled = cartridge::ram_enable(val);
+            },
+            0x2000 ... 0x3fff => {
+                // a 5 bit register where 0 is treated as 1
+                self.bank1 = val & 0x1f;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+                //println!("rom bank number {:02x}", self.bank1);
+            },
+            0x4000 ... 0x5fff => {
+                self.bank2 = val & 0x3;
+                //println!("eram bank number {:02x}", val);
+            },
+            _ => {
+                self.mode = val & 0x1;
+                //println!("rom/ram mode select {:02x}", val);
+            },
+        }
+    }
+
+    fn read_ram(&mut self, addr: u16) -> u8 {
+        match self.ram_addr(addr) {
+            Some(i) => self.ram[i],
+            None => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) {
+        if let Some(i) = self.ram_addr(addr) {
+            self.ram[i] = val;
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bankn()
+    }
+
+    fn save(&mut self, w: &mut Write) -> Result<(), io::Error> {
+        w.write_all(&self.ram)
+    }
+
+    fn load(&mut self, r: &mut Read) -> Result<(), io::Error> {
+        r.read_exact(&mut self.ram)
+    }
+
+    fn save_state(&self, w: &mut state::Writer) {
+        w.vec(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u8(self.bank1);
+        w.u8(self.bank2);
+        w.u8(self.mode);
+    }
+
+    fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        try!(r.vec(&mut self.ram));
+        self.ram_enabled = try!(r.bool());
+        self.bank1 = try!(r.u8());
+        self.bank2 = try!(r.u8());
+        self.mode = try!(r.u8());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_mbc1() {
+    // 2 MiB rom, each bank starts with its own number
+    let mut rom = vec![0; 0x200000];
+    for bank in 0..0x80 {
+        rom[bank * 0x4000] = bank as u8;
+    }
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x03;
+    let mut cart = cartridge::new(rom).unwrap();
+
+    // bank 0 selects bank 1, and the register is only 5 bits wide
+    cart.write_rom(0x2000, 0x00);
+    assert_eq!(cart.read_rom(0x4000), 0x01);
+    cart.write_rom(0x2000, 0x21);
+    assert_eq!(cart.read_rom(0x4000), 0x01);
+
+    // upper bits come from BANK2, even in mode 0
+    cart.write_rom(0x4000, 0x02);
+    assert_eq!(cart.read_rom(0x4000), 0x41);
+    assert_eq!(cart.read_rom(0x0000), 0x00);
+
+    // mode 1 also applies BANK2 to the 0x0000 region and to ram
+    cart.write_rom(0x6000, 0x01);
+    assert_eq!(cart.read_rom(0x0000), 0x40);
+
+    cart.write_rom(0x0000, 0x0a);
+    cart.write_ram(0xa000, 0x55);
+    cart.write_rom(0x4000, 0x00);
+    assert_eq!(cart.read_ram(0xa000), 0x00);
+    cart.write_rom(0x4000, 0x02);
+    assert_eq!(cart.read_ram(0xa000), 0x55);
+}
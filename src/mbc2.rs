@@ -0,0 +1,113 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge;
+use cartridge::Mapper;
+use state;
+
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: [u8; 0x200], // 512x4 bits, only the lower nibble is used
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom: rom,
+            ram: [0; 0x200],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl cartridge::Mapper for Mbc2 {
+    fn read_rom(&mut self, addr: u16) -> u8 {
+        match addr {
+            0 ... 0x3fff => cartridge::read_bank(&self.rom, 0, addr),
+            _ => cartridge::read_bank(&self.rom, self.rom_bank as usize, addr),
+        }
+    }
+
+    // address bit 8 selects between ram enable and rom bank
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        if addr >= 0x4000 {
+            return;
+        }
+        if addr & 0x100 == 0 {
+            self.ram_enabled = cartridge::ram_enable(val);
+        } else {
+            self.rom_bank = val & 0x0f;
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    // 0xa000-0xa1ff is echoed up to 0xbfff
+    fn read_ram(&mut self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        0xf0 | self.ram[(addr & 0x1ff) as usize]
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) {
+        if self.ram_enabled {
+            self.ram[(addr & 0x1ff) as usize] = val & 0x0f;
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    fn save(&mut self, w: &mut Write) -> Result<(), io::Error> {
+        w.write_all(&self.ram)
+    }
+
+    fn load(&mut self, r: &mut Read) -> Result<(), io::Error> {
+        r.read_exact(&mut self.ram)
+    }
+
+    fn save_state(&self, w: &mut state::Writer) {
+        w.bytes(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        try!(r.bytes(&mut self.ram));
+        self.ram_enabled = try!(r.bool());
+        self.rom_bank = try!(r.u8());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_mbc2() {
+    let mut rom = vec![0; 0x40000];
+    for bank in 0..0x10 {
+        rom[bank * 0x4000] = bank as u8;
+    }
+    rom[0x147] = 0x06;
+    let mut cart = cartridge::new(rom).unwrap();
+
+    // bit 8 clear is ram enable, so this must not switch banks
+    cart.write_rom(0x2000, 0x05);
+    assert_eq!(cart.read_rom(0x4000), 0x01);
+    cart.write_rom(0x2100, 0x05);
+    assert_eq!(cart.read_rom(0x4000), 0x05);
+
+    cart.write_rom(0x0000, 0x0a);
+    cart.write_ram(0xa000, 0x5a);
+    assert_eq!(cart.read_ram(0xa000), 0xfa);
+    assert_eq!(cart.read_ram(0xa200), 0xfa);
+    assert_eq!(cart.read_ram(0xbe00), 0xfa);
+}
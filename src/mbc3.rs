@@ -0,0 +1,181 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge;
+use cartridge::Mapper;
+use state;
+use rtc;
+
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool, // also enables the rtc registers
+    rom_bank: u8,      // 7 bits
+    ram_select: u8,    // ram bank (0x00-0x03) or rtc register (0x08-0x0c)
+    rtc: Option<rtc::Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rtc: bool) -> Mbc3 {
+        Mbc3 {
+            rom: rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_select: 0,
+            rtc: if has_rtc { Some(rtc::Rtc::new()) } else { None },
+        }
+    }
+}
+
+impl cartridge::Mapper for Mbc3 {
+    fn read_rom(&mut self, addr: u16) -> u8 {
+        match addr {
+            0 ... 0x3fff => cartridge::read_bank(&self.rom, 0, addr),
+            _ => cartridge::read_bank(&self.rom, self.rom_bank as usize, addr),
+        }
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0 ... 0x1fff => {
+                self.ram_enabled = cartridge::ram_enable(val);
+            },
+            // 7 bit rom bank number, 0 is treated as 1
+            0x2000 ... 0x3fff => {
+                self.rom_bank = val & 0x7f;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            },
+            0x4000 ... 0x5fff => {
+                self.ram_select = val & 0x0f;
+            },
+            // latch clock data
+            _ => {
+                if let Some(ref mut rtc) = self.rtc {
+                    rtc.write_latch(val);
+                }
+            },
+        }
+    }
+
+    fn read_ram(&mut self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        match self.ram_select {
+            0x00 ... 0x03 if self.ram.len() > 0 => {
+                let i = cartridge::ram_offset(&self.ram, self.ram_select as usize, addr);
+                self.ram[i]
+            },
+            0x08 ... 0x0c => {
+                match self.rtc {
+                    Some(ref rtc) => rtc.read(self.ram_select),
+                    None => 0xff,
+                }
+            },
+            _ => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match self.ram_select {
+            0x00 ... 0x03 if self.ram.len() > 0 => {
+                let i = cartridge::ram_offset(&self.ram, self.ram_select as usize, addr);
+                self.ram[i] = val;
+            },
+            0x08 ... 0x0c => {
+                if let Some(ref mut rtc) = self.rtc {
+                    rtc.write(self.ram_select, val);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    // the rtc block goes after the ram, like VBA and BGB
+    fn save(&mut self, w: &mut Write) -> Result<(), io::Error> {
+        try!(w.write_all(&self.ram));
+        if let Some(ref mut rtc) = self.rtc {
+            try!(w.write_all(&rtc.save()));
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, r: &mut Read) -> Result<(), io::Error> {
+        try!(r.read_exact(&mut self.ram));
+        if let Some(ref mut rtc) = self.rtc {
+            let mut buf = Vec::new();
+            try!(r.read_to_end(&mut buf));
+            rtc.load(&buf);
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, w: &mut state::Writer) {
+        w.vec(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_select);
+        if let Some(ref rtc) = self.rtc {
+            rtc.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        try!(r.vec(&mut self.ram));
+        self.ram_enabled = try!(r.bool());
+        self.rom_bank = try!(r.u8());
+        self.ram_select = try!(r.u8());
+        if let Some(ref mut rtc) = self.rtc {
+            try!(rtc.load_state(r));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_mbc3() {
+    let mut rom = vec![0; 0x200000];
+    for bank in 0..0x80 {
+        rom[bank * 0x4000] = bank as u8;
+    }
+    rom[0x147] = 0x10;
+    rom[0x149] = 0x03;
+    let mut cart = cartridge::new(rom).unwrap();
+
+    cart.write_rom(0x2000, 0x7f);
+    assert_eq!(cart.read_rom(0x4000), 0x7f);
+    cart.write_rom(0x2000, 0x00);
+    assert_eq!(cart.read_rom(0x4000), 0x01);
+
+    cart.write_rom(0x0000, 0x0a);
+    cart.write_rom(0x4000, 0x03);
+    cart.write_ram(0xa000, 0x33);
+    cart.write_rom(0x4000, 0x00);
+    assert_eq!(cart.read_ram(0xa000), 0x00);
+    cart.write_rom(0x4000, 0x03);
+    assert_eq!(cart.read_ram(0xa000), 0x33);
+
+    // rtc registers only change after a latch
+    cart.write_rom(0x4000, 0x0c);
+    cart.write_ram(0xa000, 0x40);
+    cart.write_rom(0x4000, 0x09);
+    cart.write_ram(0xa000, 42);
+    assert_eq!(cart.read_ram(0xa000), 0x00);
+    cart.write_rom(0x6000, 0x00);
+    cart.write_rom(0x6000, 0x01);
+    assert_eq!(cart.read_ram(0xa000), 42);
+}
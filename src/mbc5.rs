@@ -0,0 +1,161 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge;
+use cartridge::Mapper;
+use state;
+
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16, // 9 bits, unlike the other mappers 0 is valid
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+    rumble_changed: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            rom: rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble: has_rumble,
+            rumble: false,
+            rumble_changed: false,
+        }
+    }
+
+    fn ram_addr(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.len() == 0 {
+            return None;
+        }
+        Some(cartridge::ram_offset(&self.ram, self.ram_bank as usize, addr))
+    }
+}
+
+impl cartridge::Mapper for Mbc5 {
+    fn read_rom(&mut self, addr: u16) -> u8 {
+        match addr {
+            0 ... 0x3fff => cartridge::read_bank(&self.rom, 0, addr),
+            _ => cartridge::read_bank(&self.rom, self.rom_bank as usize, addr),
+        }
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            0 ... 0x1fff => {
+                self.ram_enabled = cartridge::ram_enable(val);
+            },
+            // low 8 bits of the rom bank
+            0x2000 ... 0x2fff => {
+                self.rom_bank = (self.rom_bank & 0x100) | val as u16;
+            },
+            // bit 8 of the rom bank
+            0x3000 ... 0x3fff => {
+                self.rom_bank = (self.rom_bank & 0xff) | ((val as u16 & 0x1) << 8);
+            },
+            // ram bank, on rumble carts bit 3 drives the motor instead
+            0x4000 ... 0x5fff => {
+                if self.has_rumble {
+                    let rumble = val & 0x08 > 0;
+                    if rumble != self.rumble {
+                        self.rumble = rumble;
+                        self.rumble_changed = true;
+                    }
+                    self.ram_bank = val & 0x07;
+                } else {
+                    self.ram_bank = val & 0x0f;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn read_ram(&mut self, addr: u16) -> u8 {
+        match self.ram_addr(addr) {
+            Some(i) => self.ram[i],
+            None => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) {
+        if let Some(i) = self.ram_addr(addr) {
+            self.ram[i] = val;
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
+
+    fn save(&mut self, w: &mut Write) -> Result<(), io::Error> {
+        w.write_all(&self.ram)
+    }
+
+    fn load(&mut self, r: &mut Read) -> Result<(), io::Error> {
+        r.read_exact(&mut self.ram)
+    }
+
+    fn save_state(&self, w: &mut state::Writer) {
+        w.vec(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u16(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.rumble);
+    }
+
+    fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        try!(r.vec(&mut self.ram));
+        self.ram_enabled = try!(r.bool());
+        self.rom_bank = try!(r.u16());
+        self.ram_bank = try!(r.u8());
+        let rumble = try!(r.bool());
+        if rumble != self.rumble {
+            self.rumble = rumble;
+            self.rumble_changed = true;
+        }
+        Ok(())
+    }
+
+    fn rumble_event(&mut self) -> Option<bool> {
+        if self.rumble_changed {
+            self.rumble_changed = false;
+            Some(self.rumble)
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_mbc5() {
+    let mut rom = vec![0; 0x800000];
+    for bank in 0..0x200 {
+        rom[bank * 0x4000] = bank as u8;
+        rom[bank * 0x4000 + 1] = (bank >> 8) as u8;
+    }
+    rom[0x147] = 0x1e;
+    rom[0x149] = 0x04;
+    let mut cart = cartridge::new(rom).unwrap();
+
+    cart.write_rom(0x2000, 0x00);
+    assert_eq!(cart.read_rom(0x4000), 0x00);
+    cart.write_rom(0x2000, 0x23);
+    cart.write_rom(0x3000, 0x01);
+    assert_eq!(cart.read_rom(0x4000), 0x23);
+    assert_eq!(cart.read_rom(0x4001), 0x01);
+
+    assert_eq!(cart.rumble_event(), None);
+    cart.write_rom(0x4000, 0x08);
+    assert_eq!(cart.rumble_event(), Some(true));
+    assert_eq!(cart.rumble_event(), None);
+}
@@ -5,19 +5,22 @@ use std::sync::RwLock;
 use std::io::prelude::*;
 use std::io;
 use std::fs::File;
+use std::path::PathBuf;
 
+use cartridge;
+use interrupt;
 use lcd;
 use timer;
 use joypad;
+use serial;
 use sound;
+use state;
 
 pub struct MemoryMap {
-    pub rom: Vec<u8>,
+    pub cart: Box<cartridge::Mapper>,
     pub vram: [u8; 0x2000],
     pub wram: [u8; 0x2000],
     pub hram: [u8; 0x80],
-    pub eram: [u8; 0x2000],
-    pub eram_enabled: bool,
     pub iobuf: [u8; 0x100],
     pub oam: [u8; 0xa0],
     pub interrupt_enable : u8,
@@ -27,10 +30,102 @@ pub struct MemoryMap {
     pub timer : Rc<RefCell<timer::Timer>>,
     pub joypad : Rc<RefCell<joypad::Joypad>>,
     pub sound : Arc<RwLock<sound::Sound>>,
-    pub rom_bank: u8,
+    pub serial : serial::Serial,
+    pub save_path : Option<PathBuf>, // battery ram file, None if the cart has no battery
+    pub vblank : bool, // set by tick when the lcd enters vblank
+    sound_cycles : u32, // cycles the sound unit has not been run for yet
 }
 
+// The sound unit is behind a lock shared with the audio thread, so it is
+// caught up in batches instead of on every memory access.
+const SOUND_BATCH_CYCLES : u32 = 1024;
+
 impl MemoryMap {
+    pub fn new(cart: Box<cartridge::Mapper>,
+               lcd: Rc<RefCell<lcd::Lcd>>,
+               timer: Rc<RefCell<timer::Timer>>,
+               joypad: Rc<RefCell<joypad::Joypad>>,
+               sound: Arc<RwLock<sound::Sound>>) -> MemoryMap {
+        MemoryMap {
+            cart: cart,
+            vram: [0; 0x2000],
+            wram: [0; 0x2000],
+            hram: [0; 0x80],
+            iobuf: [0; 0x100],
+            interrupt_enable: 0,
+            interrupt_master_enable: false,
+            interrupt_flag: 0,
+            oam: [0; 0xa0],
+            lcd: lcd,
+            timer: timer,
+            joypad: joypad,
+            sound: sound,
+            serial: serial::Serial::new(),
+            save_path: None,
+            vblank: false,
+            sound_cycles: 0,
+        }
+    }
+
+    /// A memory map around `rom` with fresh components, for tests.
+    #[cfg(test)]
+    pub fn for_test(rom: Vec<u8>) -> MemoryMap {
+        MemoryMap::new(cartridge::new(rom).unwrap(),
+                       Rc::new(RefCell::new(lcd::Lcd::new())),
+                       Rc::new(RefCell::new(timer::Timer::new())),
+                       Rc::new(RefCell::new(joypad::Joypad::new())),
+                       Arc::new(RwLock::new(sound::Sound::new())))
+    }
+
+    /// Advances everything except the cpu. Called by the cpu for every
+    /// memory access and internal delay, so `cycles` is normally 4.
+    pub fn tick(&mut self, cycles: u32) {
+        let lcd = self.lcd.clone();
+        if lcd.borrow_mut().run(self, cycles) {
+            self.vblank = true;
+        }
+        let timer = self.timer.clone();
+        timer.borrow_mut().run(self, cycles);
+        if self.serial.run(cycles) {
+            self.interrupt_flag |= interrupt::INTERRUPT_SERIAL;
+        }
+        self.sound_cycles += cycles;
+        if self.sound_cycles >= SOUND_BATCH_CYCLES {
+            self.flush_sound();
+        }
+    }
+
+    fn flush_sound(&mut self) {
+        let cycles = std::mem::replace(&mut self.sound_cycles, 0);
+        if cycles > 0 {
+            let sound = self.sound.clone();
+            sound.write().unwrap().run(self, cycles);
+        }
+    }
+
+    fn handle_cart(&mut self, addr: u16, write: bool, val: u8) -> u8 {
+        match addr {
+            0 ... 0x7fff => {
+                if write {
+                    let was_enabled = self.cart.ram_enabled();
+                    self.cart.write_rom(addr, val);
+                    if was_enabled && !self.cart.ram_enabled() {
+                        if let Err(e) = self.save_eram() {
+                            println!("error saving eram: {}", e);
+                        }
+                    }
+                }
+                self.cart.read_rom(addr)
+            },
+            _ => {
+                if write {
+                    self.cart.write_ram(addr, val);
+                }
+                self.cart.read_ram(addr)
+            },
+        }
+    }
+
     fn perform_dma(&mut self, val: u8) {
         for i in 0..0xa0 {
             let val = self.read(val as u16 * 0x100 + i);
@@ -48,14 +143,17 @@ impl MemoryMap {
                 }
                 self.joypad.borrow().flags
             }
-            0xff01 => { 0 } // serial_transfer_data
-            0xff02 => { 0 } // serial_transfer_control
-            0xff04 => { if write { self.timer.borrow_mut().div = val; } self.timer.borrow().div }
-            0xff05 => { if write { self.timer.borrow_mut().tima = val; } self.timer.borrow().tima }
-            0xff06 => { if write { self.timer.borrow_mut().tma = val; } self.timer.borrow().tma }
-            0xff07 => { if write { self.timer.borrow_mut().tac = val; } self.timer.borrow().tac }
+            0xff01 => { if write { self.serial.sb = val; } self.serial.sb }
+            0xff02 => { if write { self.serial.write_sc(val); } self.serial.read_sc() }
+            0xff04 => { if write { self.timer.borrow_mut().write_div(); } self.timer.borrow().div() }
+            0xff05 => { if write { self.timer.borrow_mut().write_tima(val); } self.timer.borrow().tima }
+            0xff06 => { if write { self.timer.borrow_mut().write_tma(val); } self.timer.borrow().tma }
+            0xff07 => { if write { self.timer.borrow_mut().write_tac(val); } self.timer.borrow().read_tac() }
 
-            0xff10 ... 0xff3f => { self.sound.write().unwrap().handle_addr(addr, write, val) }
+            0xff10 ... 0xff3f => {
+                self.flush_sound();
+                self.sound.write().unwrap().handle_addr(addr, write, val)
+            }
 
             0xff40 => { if write { self.lcd.borrow_mut().ctl = val; } self.lcd.borrow().ctl }
             0xff41 => { if write { self.lcd.borrow_mut().stat = val; } self.lcd.borrow().stat }
@@ -83,47 +181,9 @@ impl MemoryMap {
 
     fn handle_addr(&mut self, addr: u16, write: bool, val: u8) -> u8 {
         match addr {
-            // rom bank 0
-            0 ... 0x1fff => {
-                if write {
-                    if (val & 0xf) == 0xa {
-                        if !self.eram_enabled {
-                            println!("enabling eram");
-                            self.eram_enabled = true;
-                        }
-                    } else {
-                        if self.eram_enabled {
-                            println!("disabling eram");
-                            self.eram_enabled = false;
-                            self.save_eram();
-                        }
-                    }
-                }
-                self.rom[addr as usize]
-            },
-            0x2000 ... 0x3fff => {
-                if write {
-                    if val == 0x00 || val == 0x20 || val == 0x40 || val == 0x60 {
-                        self.rom_bank = val + 1;
-                    } else {
-                        self.rom_bank = val;
-                    }
-                    //println!("rom bank number {:02x}", self.rom_bank);
-                }
-                self.rom[addr as usize]
-            },
-            // rom bank n
-            0x4000 ... 0x5fff => {
-                if write {
-                    //println!("eram bank number {:02x}", val);
-                }
-                self.rom[self.rom_bank as usize * 0x4000 + (addr - 0x4000) as usize]
-            },
-            0x6000 ... 0x7fff => {
-                if write {
-                    //println!("rom/ram mode select {:02x}", val);
-                }
-                self.rom[self.rom_bank as usize * 0x4000 + (addr - 0x4000) as usize]
+            // rom
+            0 ... 0x7fff => {
+                self.handle_cart(addr, write, val)
             },
             // vram
             0x8000 ... 0x9fff => {
@@ -134,10 +194,7 @@ impl MemoryMap {
             },
             // eram
             0xa000 ... 0xbfff => {
-                if write {
-                    self.eram[addr as usize - 0xa000] = val;
-                }
-                self.eram[addr as usize - 0xa000]
+                self.handle_cart(addr, write, val)
             },
             // wram
             0xc000 ... 0xdfff => {
@@ -191,6 +248,36 @@ impl MemoryMap {
         }
     }
 
+    pub fn save_state(&self, w: &mut state::Writer) {
+        w.bytes(&self.vram);
+        w.bytes(&self.wram);
+        w.bytes(&self.hram);
+        w.bytes(&self.iobuf);
+        w.bytes(&self.oam);
+        w.u8(self.interrupt_enable);
+        w.bool(self.interrupt_master_enable);
+        w.u8(self.interrupt_flag);
+        w.u32(self.sound_cycles);
+        w.bool(self.vblank);
+        self.serial.save_state(w);
+        self.cart.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        try!(r.bytes(&mut self.vram));
+        try!(r.bytes(&mut self.wram));
+        try!(r.bytes(&mut self.hram));
+        try!(r.bytes(&mut self.iobuf));
+        try!(r.bytes(&mut self.oam));
+        self.interrupt_enable = try!(r.u8());
+        self.interrupt_master_enable = try!(r.bool());
+        self.interrupt_flag = try!(r.u8());
+        self.sound_cycles = try!(r.u32());
+        self.vblank = try!(r.bool());
+        try!(self.serial.load_state(r));
+        self.cart.load_state(r)
+    }
+
     pub fn dump(&mut self, start: u16, len: u16) {
         for x in 0..len/32 {
             println!("{:04x}: {:02x} {:02x} {:02x} {:02x}  {:02x} {:02x} {:02x} {:02x}  {:02x} {:02x} {:02x} {:02x}  {:02x} {:02x} {:02x} {:02x}   {:02x} {:02x} {:02x} {:02x}  {:02x} {:02x} {:02x} {:02x}  {:02x} {:02x} {:02x} {:02x}  {:02x} {:02x} {:02x} {:02x}",
@@ -242,32 +329,26 @@ impl MemoryMap {
         self.interrupt_master_enable = false;
     }
 
-    pub fn ei(&mut self) {
-        self.interrupt_master_enable = true;
-    }
-
-    pub fn interrupt_triggered(&mut self, interrupt: u8) -> bool {
-        if !self.interrupt_master_enable {
-            return false;
-        }
-
-        let triggered = self.interrupt_enable & interrupt > 0 && self.interrupt_flag & interrupt > 0;
-        if triggered {
-            self.interrupt_master_enable = false;
-            self.interrupt_flag &= !interrupt;
-        }
-        return triggered;
+    /// Interrupts that are both requested and enabled, regardless of IME.
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_enable & self.interrupt_flag & interrupt::INTERRUPT_MASK
     }
 
     pub fn load_eram(&mut self) -> Result<(), io::Error> {
-        let mut f = try!(File::open("eram"));
-        try!(f.read_exact(&mut self.eram));
-        Ok(())
+        let path = match self.save_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let mut f = try!(File::open(path));
+        self.cart.load(&mut f)
     }
 
     pub fn save_eram(&mut self) -> Result<(), io::Error> {
-        let mut f = try!(File::create("eram"));
-        try!(f.write_all(&self.eram));
-        Ok(())
+        let path = match self.save_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let mut f = try!(File::create(path));
+        self.cart.save(&mut f)
     }
 }
@@ -0,0 +1,333 @@
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+use image;
+use serial::SerialDevice;
+
+// Game Boy Printer. The game sends packets of
+//
+//   0x88 0x33 command compression length(2) data(length) checksum(2) 0x00 0x00
+//
+// with the 16 bit values little endian and the checksum covering everything
+// from the command to the end of the data. The printer answers 0x81 to the
+// first of the two trailing zeros and its status to the second, and 0x00 to
+// everything else.
+//
+// Data packets hold 2bpp tiles, 20 to a row, so a full 640 byte packet is a
+// 160x16 strip. The print packet renders everything received so far with
+// its palette. Strips pile up on the same page until a print asks for a
+// feed after it, then the page is saved as the next numbered png.
+
+const CMD_INIT   : u8 = 0x01;
+const CMD_PRINT  : u8 = 0x02;
+const CMD_DATA   : u8 = 0x04;
+const CMD_STATUS : u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR : u8 = 1<<0;
+const STATUS_PRINTING       : u8 = 1<<1;
+const STATUS_FULL           : u8 = 1<<2;
+const STATUS_UNPROCESSED    : u8 = 1<<3;
+
+const WIDTH : usize = 160;
+const BYTES_PER_TILE_ROW : usize = 20 * 16;
+const BUFFER_SIZE : usize = 0x2000;
+
+// How many status packets report the printer as busy after a print.
+const PRINT_STATUS_POLLS : u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    dir: PathBuf,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    buffer: Vec<u8>,  // tile data waiting to be printed
+    page: Vec<u8>,    // shades of the strips printed on the current page
+    status: u8,
+    busy: u8,
+    next_page: usize,
+}
+
+/// Expands the printer's run length encoding: a control byte with the top
+/// bit set repeats the next byte (control & 0x7f) + 2 times, otherwise the
+/// next control + 1 bytes are copied as they are.
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 > 0 {
+            if let Some(&val) = data.get(i) {
+                for _ in 0..(control & 0x7f) as usize + 2 {
+                    out.push(val);
+                }
+            }
+            i += 1;
+        } else {
+            let end = ::std::cmp::min(i + control as usize + 1, data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+/// Converts 2bpp tile data into one shade (0-3) per pixel using a BGP
+/// style palette. The result is 160 pixels wide, a partial last tile row
+/// is padded with shade 0.
+pub fn render(tiles: &[u8], palette: u8) -> Vec<u8> {
+    let rows = (tiles.len() / 16 + 19) / 20 * 8;
+    let mut shades = vec![0; rows * WIDTH];
+    for (t, tile) in tiles.chunks(16).enumerate() {
+        if tile.len() < 16 {
+            break;
+        }
+        let tx = (t % 20) * 8;
+        let ty = (t / 20) * 8;
+        for y in 0..8 {
+            let (lo, hi) = (tile[y * 2], tile[y * 2 + 1]);
+            for x in 0..8 {
+                let bit = 7 - x;
+                let color = ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1);
+                shades[(ty + y) * WIDTH + tx + x] = (palette >> (color * 2)) & 3;
+            }
+        }
+    }
+    shades
+}
+
+impl Printer {
+    /// Saves the printed pages in `dir` as print-001.png, print-002.png...
+    /// skipping numbers that are already taken.
+    pub fn new(dir: PathBuf) -> Printer {
+        Printer {
+            dir: dir,
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            status: 0,
+            busy: 0,
+            next_page: 1,
+        }
+    }
+
+    fn execute(&mut self) {
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy = 0;
+            }
+            CMD_DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                let room = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..::std::cmp::min(room, data.len())]);
+                self.status |= STATUS_UNPROCESSED;
+                if self.buffer.len() >= BUFFER_SIZE {
+                    self.status |= STATUS_FULL;
+                }
+            }
+            CMD_PRINT if self.data.len() >= 4 => {
+                let sheets = self.data[0];
+                let feed_after = self.data[1] & 0x0f;
+                // palette 0 means the usual 0xe4
+                let palette = if self.data[2] == 0 { 0xe4 } else { self.data[2] };
+                if sheets > 0 {
+                    let shades = render(&self.buffer, palette);
+                    self.page.extend_from_slice(&shades);
+                }
+                self.buffer.clear();
+                self.status &= !(STATUS_UNPROCESSED | STATUS_FULL);
+                self.busy = PRINT_STATUS_POLLS;
+                if feed_after > 0 {
+                    if let Err(e) = self.save_page() {
+                        println!("printer: {}", e);
+                    }
+                }
+            }
+            CMD_STATUS => {
+                self.busy = self.busy.saturating_sub(1);
+            }
+            _ => {}
+        }
+        if self.busy > 0 {
+            self.status |= STATUS_PRINTING;
+        } else {
+            self.status &= !STATUS_PRINTING;
+        }
+    }
+
+    /// Writes the current page to the next free numbered png, if anything
+    /// was printed on it. Returns the file name.
+    pub fn save_page(&mut self) -> Result<Option<PathBuf>, io::Error> {
+        if self.page.is_empty() {
+            return Ok(None);
+        }
+        let path = loop {
+            let path = self.dir.join(format!("print-{:03}.png", self.next_page));
+            self.next_page += 1;
+            if !path.exists() {
+                break path;
+            }
+        };
+        let rgb : Vec<u8> = self.page.iter().flat_map(|shade| {
+            let v = [255, 170, 85, 0][*shade as usize];
+            vec![v, v, v]
+        }).collect();
+        let mut f = try!(File::create(&path));
+        try!(image::write_png(&mut f, WIDTH, self.page.len() / WIDTH, &rgb));
+        self.page.clear();
+        println!("printed {}", path.display());
+        Ok(Some(path))
+    }
+}
+
+impl Drop for Printer {
+    // don't lose a page the game never fed out
+    fn drop(&mut self) {
+        if let Err(e) = self.save_page() {
+            println!("printer: {}", e);
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, out: u8) -> u8 {
+        let mut reply = 0;
+        self.state = match self.state {
+            State::Magic1 => if out == 0x88 { State::Magic2 } else { State::Magic1 },
+            State::Magic2 => match out {
+                0x33 => State::Command,
+                0x88 => State::Magic2,
+                _ => State::Magic1,
+            },
+            State::Command => {
+                self.command = out;
+                self.checksum = out as u16;
+                self.data.clear();
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = out & 1 > 0;
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = out as usize;
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (out as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                if self.length > 0 { State::Data } else { State::ChecksumLow }
+            }
+            State::Data => {
+                self.data.push(out);
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                if self.data.len() < self.length { State::Data } else { State::ChecksumLow }
+            }
+            State::ChecksumLow => {
+                self.checksum = self.checksum.wrapping_sub(out as u16);
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.checksum = self.checksum.wrapping_sub((out as u16) << 8);
+                if self.checksum == 0 {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.execute();
+                } else {
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+                State::Alive
+            }
+            State::Alive => {
+                reply = 0x81;
+                State::Status
+            }
+            State::Status => {
+                reply = self.status;
+                State::Magic1
+            }
+        };
+        reply
+    }
+}
+
+#[test]
+fn test_printer() {
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut p = vec![0x88, 0x33, command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        p.extend_from_slice(data);
+        let sum = p[2..].iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+        p.extend_from_slice(&[sum as u8, (sum >> 8) as u8, 0, 0]);
+        p
+    }
+
+    assert_eq!(decompress(&[0x81, 0xaa, 0x01, 1, 2]), vec![0xaa, 0xaa, 0xaa, 1, 2]);
+    // one tile row of solid color 3 tiles
+    assert_eq!(render(&vec![0xff; BYTES_PER_TILE_ROW], 0xe4), vec![3; WIDTH * 8]);
+    // a tile row and one tile
+    let shades = render(&[0xff; 21 * 16], 0xe4);
+    assert_eq!(shades.len(), WIDTH * 16);
+    assert_eq!(&shades[WIDTH * 8..WIDTH * 8 + 8], &[3; 8]);
+    assert_eq!(shades[WIDTH * 8 + 8], 0);
+    // a full buffer is 25 tile rows and 12 tiles
+    assert_eq!(render(&[0xff; BUFFER_SIZE], 0xe4).len(), WIDTH * 26 * 8);
+
+    let dir = env::temp_dir().join(format!("rustboy-printer-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut printer = Printer::new(dir.clone());
+    let mut replies = Vec::new();
+    let mut send = |printer: &mut Printer, bytes: Vec<u8>| {
+        let r : Vec<u8> = bytes.iter().map(|b| printer.transfer(*b)).collect();
+        replies.push((r[r.len() - 2], r[r.len() - 1]));
+    };
+    send(&mut printer, packet(CMD_INIT, false, &[]));
+    // 640 bytes of 0xff, compressed as five runs of 128
+    send(&mut printer, packet(CMD_DATA, true, &[0xfe, 0xff, 0xfe, 0xff, 0xfe, 0xff, 0xfe, 0xff, 0xfe, 0xff]));
+    send(&mut printer, packet(CMD_DATA, false, &[]));
+    send(&mut printer, packet(CMD_PRINT, false, &[1, 0x13, 0xe4, 0x40]));
+    send(&mut printer, packet(CMD_STATUS, false, &[]));
+    let mut bad = packet(CMD_STATUS, false, &[]);
+    bad[6] ^= 1;
+    send(&mut printer, bad);
+
+    assert_eq!(replies, vec![(0x81, 0x00), (0x81, STATUS_UNPROCESSED), (0x81, STATUS_UNPROCESSED),
+                             (0x81, STATUS_PRINTING), (0x81, STATUS_PRINTING),
+                             (0x81, STATUS_PRINTING | STATUS_CHECKSUM_ERROR)]);
+    let mut png = Vec::new();
+    File::open(dir.join("print-001.png")).unwrap().read_to_end(&mut png).unwrap();
+    assert_eq!(&png[1..4], b"PNG");
+    // 160x16
+    assert_eq!(&png[16..24], &[0, 0, 0, 160, 0, 0, 0, 16]);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use state;
+
+// MBC3 real time clock. The counter is advanced from the host clock whenever
+// it is touched, so it keeps running while the emulator is closed as long as
+// the timestamp is saved with the battery ram.
+pub struct Rtc {
+    seconds : u8,
+    minutes : u8,
+    hours : u8,
+    days : u16,    // 9 bits
+    halt : bool,
+    carry : bool,  // day counter overflowed
+    latched : [u8; 5],
+    latch_armed : bool,
+    last_update : u64, // unix time the counter was last advanced
+}
+
+const RTC_DH_DAY_HIGH  : u8 = 1<<0;
+const RTC_DH_HALT      : u8 = 1<<6;
+const RTC_DH_DAY_CARRY : u8 = 1<<7;
+
+// Length of the rtc block appended to the .sav file. This is the layout
+// used by VBA and BGB: five current registers and five latched registers as
+// 32 bit little endian values followed by a 64 bit unix timestamp. Some
+// emulators only store a 32 bit timestamp, which makes the block 44 bytes.
+pub const RTC_SAVE_LEN : usize = 48;
+const RTC_SAVE_LEN_SHORT : usize = 44;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl fmt::Debug for Rtc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Rtc {{ days:{} {:02}:{:02}:{:02} halt:{} carry:{} }}",
+               self.days, self.hours, self.minutes, self.seconds,
+               self.halt, self.carry)
+    }
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            latch_armed: false,
+            last_update: now(),
+        }
+    }
+
+    fn dh(&self) -> u8 {
+        let mut dh = (self.days >> 8) as u8 & RTC_DH_DAY_HIGH;
+        if self.halt { dh |= RTC_DH_HALT; }
+        if self.carry { dh |= RTC_DH_DAY_CARRY; }
+        dh
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [self.seconds, self.minutes, self.hours, self.days as u8, self.dh()]
+    }
+
+    pub fn advance(&mut self, secs: u64) {
+        let total = self.seconds as u64 + secs;
+        self.seconds = (total % 60) as u8;
+        let total = self.minutes as u64 + total / 60;
+        self.minutes = (total % 60) as u8;
+        let total = self.hours as u64 + total / 60;
+        self.hours = (total % 24) as u8;
+        let total = self.days as u64 + total / 24;
+        if total > 0x1ff {
+            self.carry = true;
+        }
+        self.days = (total & 0x1ff) as u16;
+    }
+
+    fn update(&mut self) {
+        let t = now();
+        if !self.halt && t > self.last_update {
+            let secs = t - self.last_update;
+            self.advance(secs);
+        }
+        self.last_update = t;
+    }
+
+    /// Writing 0 followed by 1 copies the counter into the latched registers.
+    pub fn write_latch(&mut self, val: u8) {
+        if val == 0 {
+            self.latch_armed = true;
+        } else {
+            if val == 1 && self.latch_armed {
+                self.update();
+                self.latched = self.registers();
+            }
+            self.latch_armed = false;
+        }
+    }
+
+    /// Reads one of the latched registers 0x08-0x0c.
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 ... 0x0c => self.latched[(reg - 0x08) as usize],
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        self.update();
+        match reg {
+            0x08 => { self.seconds = val & 0x3f; }
+            0x09 => { self.minutes = val & 0x3f; }
+            0x0a => { self.hours = val & 0x1f; }
+            0x0b => { self.days = (self.days & 0x100) | val as u16; }
+            0x0c => {
+                self.days = (self.days & 0xff) | ((val & RTC_DH_DAY_HIGH) as u16) << 8;
+                self.halt = val & RTC_DH_HALT > 0;
+                self.carry = val & RTC_DH_DAY_CARRY > 0;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn save(&mut self) -> Vec<u8> {
+        self.update();
+        let mut buf = Vec::with_capacity(RTC_SAVE_LEN);
+        let regs = self.registers();
+        for r in regs.iter().chain(self.latched.iter()) {
+            buf.extend_from_slice(&[*r, 0, 0, 0]);
+        }
+        for i in 0..8 {
+            buf.push((self.last_update >> (i * 8)) as u8);
+        }
+        buf
+    }
+
+    pub fn save_state(&self, w: &mut state::Writer) {
+        for r in self.registers().iter().chain(self.latched.iter()) {
+            w.u8(*r);
+        }
+        w.bool(self.latch_armed);
+        w.u64(self.last_update);
+    }
+
+    pub fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        self.seconds = try!(r.u8());
+        self.minutes = try!(r.u8());
+        self.hours = try!(r.u8());
+        let dl = try!(r.u8());
+        let dh = try!(r.u8());
+        self.days = dl as u16 | ((dh & RTC_DH_DAY_HIGH) as u16) << 8;
+        self.halt = dh & RTC_DH_HALT > 0;
+        self.carry = dh & RTC_DH_DAY_CARRY > 0;
+        try!(r.bytes(&mut self.latched));
+        self.latch_armed = try!(r.bool());
+        self.last_update = try!(r.u64());
+        Ok(())
+    }
+
+    pub fn load(&mut self, buf: &[u8]) {
+        if buf.len() < RTC_SAVE_LEN_SHORT {
+            return;
+        }
+        let reg = |i: usize| buf[i * 4];
+        self.seconds = reg(0) % 60;
+        self.minutes = reg(1) % 60;
+        self.hours = reg(2) % 24;
+        self.days = reg(3) as u16 | ((reg(4) & RTC_DH_DAY_HIGH) as u16) << 8;
+        self.halt = reg(4) & RTC_DH_HALT > 0;
+        self.carry = reg(4) & RTC_DH_DAY_CARRY > 0;
+        for i in 0..5 {
+            self.latched[i] = reg(5 + i);
+        }
+        self.last_update = 0;
+        let timestamp_len = if buf.len() >= RTC_SAVE_LEN { 8 } else { 4 };
+        for i in 0..timestamp_len {
+            self.last_update |= (buf[40 + i] as u64) << (i * 8);
+        }
+        // catch up on the time that passed while the emulator was closed
+        self.update();
+    }
+}
+
+#[test]
+fn test_rtc() {
+    let mut rtc = Rtc::new();
+    rtc.write(0x0c, RTC_DH_HALT);
+    rtc.write(0x08, 59);
+    rtc.write(0x09, 59);
+    rtc.write(0x0a, 23);
+    rtc.write(0x0b, 0xff);
+    rtc.write(0x0c, RTC_DH_HALT | RTC_DH_DAY_HIGH);
+    rtc.advance(1);
+    rtc.write_latch(0);
+    rtc.write_latch(1);
+    assert_eq!(rtc.read(0x08), 0);
+    assert_eq!(rtc.read(0x0b), 0);
+    assert_eq!(rtc.read(0x0c), RTC_DH_HALT | RTC_DH_DAY_CARRY);
+}
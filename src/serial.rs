@@ -0,0 +1,185 @@
+use std::fmt;
+use std::io::prelude::*;
+use std::io;
+use state;
+
+const SC_TRANSFER_START : u8 = 1<<7;
+const SC_INTERNAL_CLOCK : u8 = 1<<0;
+
+// The internal clock shifts one bit at 8192 Hz.
+pub const CYCLES_PER_BYTE : u32 = 8 * 512;
+
+/// Whatever is plugged into the link port.
+pub trait SerialDevice {
+    /// Exchanges a byte when the gameboy drives the clock. `out` is the byte
+    /// that was shifted out, the result is the byte shifted in.
+    fn transfer(&mut self, out: u8) -> u8;
+
+    /// Called when the gameboy starts driving the clock, or stops before the
+    /// transfer is done. A started transfer ends with `transfer` exactly
+    /// CYCLES_PER_BYTE cycles later.
+    fn set_clock(&mut self, driving: bool) {
+        let _ = driving;
+    }
+
+    /// Called whenever the gameboy runs. `out` is SB while a transfer waits
+    /// for the external clock; if the other side clocked a byte, exchanges
+    /// `out` for it.
+    fn tick(&mut self, cycles: u32, out: Option<u8>) -> Option<u8> {
+        let _ = (cycles, out);
+        None
+    }
+}
+
+/// Nothing connected: the data line floats high, so the gameboy reads 0xff
+/// and external clock transfers never finish.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _: u8) -> u8 {
+        0xff
+    }
+}
+
+pub struct Serial {
+    pub sb : u8, // Serial Transfer Data (R/W)
+    pub sc : u8, // Serial Transfer Control (R/W)
+    cycles : u32, // into the current transfer
+    device : Box<SerialDevice>,
+    log : Option<Box<Write>>, // gets every byte the gameboy sends
+}
+
+impl fmt::Debug for Serial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Serial {{ sb:{:02x} sc:{:02x} cycles:{} }}", self.sb, self.sc, self.cycles)
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            cycles: 0,
+            device: Box::new(Disconnected),
+            log: None,
+        }
+    }
+
+    /// Mirrors every byte the gameboy sends to `out`, whatever is connected.
+    pub fn set_log(&mut self, out: Box<Write>) {
+        self.log = Some(out);
+    }
+
+    fn log_byte(&mut self, val: u8) {
+        let result = match self.log {
+            Some(ref mut out) => out.write_all(&[val]).and_then(|_| if val == b'\n' { out.flush() } else { Ok(()) }),
+            None => return,
+        };
+        if let Err(e) = result {
+            println!("error writing serial log: {}", e);
+            self.log = None;
+        }
+    }
+
+    /// Plugs `device` into the link port, returning the old one.
+    pub fn connect(&mut self, device: Box<SerialDevice>) -> Box<SerialDevice> {
+        ::std::mem::replace(&mut self.device, device)
+    }
+
+    pub fn read_sc(&self) -> u8 {
+        // the unused bits read back as 1
+        self.sc | 0x7e
+    }
+
+    fn driving_clock(&self) -> bool {
+        self.sc & (SC_TRANSFER_START | SC_INTERNAL_CLOCK) == SC_TRANSFER_START | SC_INTERNAL_CLOCK
+    }
+
+    pub fn write_sc(&mut self, val: u8) {
+        let was_driving = self.driving_clock();
+        self.sc = val & (SC_TRANSFER_START | SC_INTERNAL_CLOCK);
+        self.cycles = 0;
+        if was_driving || self.driving_clock() {
+            let driving = self.driving_clock();
+            self.device.set_clock(driving);
+        }
+    }
+
+    pub fn save_state(&self, w: &mut state::Writer) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u32(self.cycles);
+    }
+
+    pub fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        self.sb = try!(r.u8());
+        self.sc = try!(r.u8());
+        self.cycles = try!(r.u32());
+        Ok(())
+    }
+
+    /// Returns true when a transfer finished, which raises the serial
+    /// interrupt. The byte is exchanged with the device all at once at the
+    /// end, so SB keeps its old value while the transfer is in progress.
+    pub fn run(&mut self, cycles: u32) -> bool {
+        let waiting = self.sc & (SC_TRANSFER_START | SC_INTERNAL_CLOCK) == SC_TRANSFER_START;
+        let out = self.sb;
+        if let Some(val) = self.device.tick(cycles, if waiting { Some(out) } else { None }) {
+            self.log_byte(out);
+            self.sb = val;
+        } else {
+            if !self.driving_clock() {
+                return false;
+            }
+            self.cycles += cycles;
+            if self.cycles < CYCLES_PER_BYTE {
+                return false;
+            }
+            self.log_byte(out);
+            self.sb = self.device.transfer(out);
+        }
+        self.sc &= !SC_TRANSFER_START;
+        self.cycles = 0;
+        true
+    }
+}
+
+#[test]
+fn test_serial() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use trace::SharedBuf;
+
+    struct Echo(Rc<RefCell<Vec<u8>>>);
+    impl SerialDevice for Echo {
+        fn transfer(&mut self, out: u8) -> u8 {
+            self.0.borrow_mut().push(out);
+            out.wrapping_add(1)
+        }
+    }
+
+    let mut serial = Serial::new();
+    serial.sb = 0x42;
+    serial.write_sc(0x81);
+    assert_eq!(serial.read_sc(), 0xff);
+    assert!(!serial.run(CYCLES_PER_BYTE - 4));
+    assert!(serial.run(4));
+    assert_eq!(serial.sb, 0xff);
+    assert_eq!(serial.read_sc(), 0x7f);
+
+    // external clock with nothing connected never finishes
+    serial.write_sc(0x80);
+    assert!(!serial.run(CYCLES_PER_BYTE * 4));
+
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    let log = SharedBuf::new();
+    serial.connect(Box::new(Echo(sent.clone())));
+    serial.set_log(Box::new(log.clone()));
+    serial.sb = 0x10;
+    serial.write_sc(0x81);
+    assert!(serial.run(CYCLES_PER_BYTE));
+    assert_eq!(serial.sb, 0x11);
+    assert_eq!(*sent.borrow(), vec![0x10]);
+    assert_eq!(log.contents(), vec![0x10]);
+}
@@ -1,15 +1,14 @@
 use std::cell::RefCell;
 use std::fmt;
+use std::io;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::vec::Vec;
 
-use sdl2::audio::AudioCallback;
-use sdl2::audio::AudioSpec;
-
 use mem;
 use interrupt;
+use state;
 
 pub struct Sound {
     // channel 1 - tone and sweep
@@ -61,7 +60,7 @@ pub struct Sound {
 
 
 pub struct SoundPlayer {
-    pub spec : AudioSpec,
+    pub freq : i32,
     pub volume : f32,
     pub x : u8,
     pub phase : f32,
@@ -72,33 +71,6 @@ pub struct SoundPlayer {
     pub samples : Vec<u8>,
 }
 
-impl AudioCallback for SoundPlayer {
-    type Channel = f32;
-
-    fn callback(&mut self, out: &mut [f32]) {
-        for i in 0..self.spec.samples {
-            self.samples[i as usize] = 0;
-        }
-
-        {
-            let s = self.sound.read().unwrap();
-
-            if s.nr52 & 0x80 == 0 {
-                return;
-            }
-        }
-
-        self.handle_channel1();
-        self.handle_channel2();
-        self.handle_channel3();
-        self.handle_channel4();
-
-        for i in 0..self.spec.samples {
-            out[i as usize] = -1.0 + self.samples[i as usize] as f32 / 45.0;
-        }
-    }
-}
-
 impl fmt::Debug for Sound {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Sound {{ \n\
@@ -145,13 +117,52 @@ fn pow(a: u32, b: u32) -> u32 {
 
 impl SoundPlayer {
 
+    pub fn new(sound: Arc<RwLock<Sound>>, freq: i32) -> SoundPlayer {
+        SoundPlayer {
+            freq: freq,
+            volume: 0.05,
+            x: 5,
+            phase: 0.0,
+            phase2: 0.0,
+            phase3: 0.0,
+            phase4: 0.0,
+            sound: sound,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        self.samples.clear();
+        self.samples.resize(out.len(), 0);
+
+        {
+            let s = self.sound.read().unwrap();
+
+            if s.nr52 & 0x80 == 0 {
+                for sample in out.iter_mut() {
+                    *sample = 0.0;
+                }
+                return;
+            }
+        }
+
+        self.handle_channel1();
+        self.handle_channel2();
+        self.handle_channel3();
+        self.handle_channel4();
+
+        for i in 0..out.len() {
+            out[i] = -1.0 + self.samples[i] as f32 / 45.0;
+        }
+    }
+
     fn handle_channel1(&mut self) {
         let mut s = self.sound.write().unwrap();
 
         let freq_lo = s.nr13 as u32;
         let freq_hi = s.nr14 as u32 & 0b111;
         let freq = 131072 / (2048 - (freq_hi << 8 | freq_lo));
-        let phase_inc = freq as f32 / self.spec.freq as f32;
+        let phase_inc = freq as f32 / self.freq as f32;
         let wave_duty = s.nr11 >> 6;
 
         let phase_val = match wave_duty {
@@ -177,7 +188,7 @@ impl SoundPlayer {
         let freq_lo = s.nr23 as u32;
         let freq_hi = s.nr24 as u32 & 0b111;
         let freq = 131072 / (2048 - (freq_hi << 8 | freq_lo));
-        let phase_inc = freq as f32 / self.spec.freq as f32;
+        let phase_inc = freq as f32 / self.freq as f32;
         let wave_duty = s.nr21 >> 6;
 
         let phase_val = match wave_duty {
@@ -206,7 +217,7 @@ impl SoundPlayer {
         let freq_lo = s.nr33 as u32;
         let freq_hi = s.nr34 as u32 & 0b111;
         let freq = 65536 / (2048 - (freq_hi << 8 | freq_lo)) * 32;
-        let phase_inc = freq as f32 / self.spec.freq as f32;
+        let phase_inc = freq as f32 / self.freq as f32;
 
         let volume_divisor = match s.nr32 & 0b1100000 >> 5 {
             0 => { 1 }
@@ -246,7 +257,7 @@ impl SoundPlayer {
         let mut p = pow(2, s);
         if p == 0 { p = 1; }
         let freq = 524288 as f32 / r / p as f32;
-        let phase_inc = freq as f32 / self.spec.freq as f32;
+        let phase_inc = freq as f32 / self.freq as f32;
 
         println!("ch 4 vol={}", sound.ch4_volume);
 
@@ -300,6 +311,79 @@ impl Sound {
         }
     }
 
+    pub fn save_state(&self, w: &mut state::Writer) {
+        for r in &[self.nr10, self.nr11, self.nr12, self.nr13, self.nr14] {
+            w.u8(*r);
+        }
+        w.u32(self.ch1_length_cycles);
+        w.u8(self.ch1_volume);
+        w.u32(self.ch1_envelope_cycles);
+
+        for r in &[self.nr21, self.nr22, self.nr23, self.nr24] {
+            w.u8(*r);
+        }
+        w.u32(self.ch2_length_cycles);
+        w.u8(self.ch2_volume);
+        w.u32(self.ch2_envelope_cycles);
+
+        for r in &[self.nr30, self.nr31, self.nr32, self.nr33, self.nr34] {
+            w.u8(*r);
+        }
+        w.bytes(&self.wave_ram);
+        w.u32(self.ch3_counter as u32);
+
+        for r in &[self.nr41, self.nr42, self.nr43, self.nr44] {
+            w.u8(*r);
+        }
+        w.u32(self.ch4_length_cycles);
+        w.u8(self.ch4_volume);
+        w.u32(self.ch4_envelope_cycles);
+
+        for r in &[self.nr50, self.nr51, self.nr52] {
+            w.u8(*r);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        self.nr10 = try!(r.u8());
+        self.nr11 = try!(r.u8());
+        self.nr12 = try!(r.u8());
+        self.nr13 = try!(r.u8());
+        self.nr14 = try!(r.u8());
+        self.ch1_length_cycles = try!(r.u32());
+        self.ch1_volume = try!(r.u8());
+        self.ch1_envelope_cycles = try!(r.u32());
+
+        self.nr21 = try!(r.u8());
+        self.nr22 = try!(r.u8());
+        self.nr23 = try!(r.u8());
+        self.nr24 = try!(r.u8());
+        self.ch2_length_cycles = try!(r.u32());
+        self.ch2_volume = try!(r.u8());
+        self.ch2_envelope_cycles = try!(r.u32());
+
+        self.nr30 = try!(r.u8());
+        self.nr31 = try!(r.u8());
+        self.nr32 = try!(r.u8());
+        self.nr33 = try!(r.u8());
+        self.nr34 = try!(r.u8());
+        try!(r.bytes(&mut self.wave_ram));
+        self.ch3_counter = try!(r.u32()) as usize % 32;
+
+        self.nr41 = try!(r.u8());
+        self.nr42 = try!(r.u8());
+        self.nr43 = try!(r.u8());
+        self.nr44 = try!(r.u8());
+        self.ch4_length_cycles = try!(r.u32());
+        self.ch4_volume = try!(r.u8());
+        self.ch4_envelope_cycles = try!(r.u32());
+
+        self.nr50 = try!(r.u8());
+        self.nr51 = try!(r.u8());
+        self.nr52 = try!(r.u8());
+        Ok(())
+    }
+
     pub fn run(&mut self, mm: &mut mem::MemoryMap, cycles: u32) {
         //println!("{:?}", self);
 
@@ -0,0 +1,175 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Save state file layout: the magic, a version number and then every
+// component in a fixed order. Bump the version whenever a component adds,
+// removes or reorders a field so old states are rejected instead of being
+// loaded into the wrong place.
+pub const STATE_MAGIC : &'static [u8] = b"RBST";
+pub const STATE_VERSION : u32 = 9;
+
+/// Save states for a rom live next to it, game.gb -> game.ss1.
+pub fn state_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("ss{}", slot))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.buf.push(if val { 1 } else { 0 });
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.buf.push(val as u8);
+        self.buf.push((val >> 8) as u8);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        for i in 0..4 {
+            self.buf.push((val >> (i * 8)) as u8);
+        }
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        for i in 0..8 {
+            self.buf.push((val >> (i * 8)) as u8);
+        }
+    }
+
+    /// Fixed size data, the reader must know the length.
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+    }
+
+    /// Variable size data, prefixed with its length.
+    pub fn vec(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.buf.extend_from_slice(val);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf: buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        if self.pos + len > self.buf.len() {
+            return Err(invalid("save state is truncated"));
+        }
+        let data = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(data)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, io::Error> {
+        Ok(try!(self.take(1))[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, io::Error> {
+        Ok(try!(self.u8()) != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, io::Error> {
+        let b = try!(self.take(2));
+        Ok(b[0] as u16 | (b[1] as u16) << 8)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, io::Error> {
+        let b = try!(self.take(4));
+        let mut val = 0u32;
+        for i in 0..4 {
+            val |= (b[i] as u32) << (i * 8);
+        }
+        Ok(val)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, io::Error> {
+        let b = try!(self.take(8));
+        let mut val = 0u64;
+        for i in 0..8 {
+            val |= (b[i] as u64) << (i * 8);
+        }
+        Ok(val)
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), io::Error> {
+        let len = out.len();
+        out.copy_from_slice(try!(self.take(len)));
+        Ok(())
+    }
+
+    /// Reads data written with `Writer::vec`. The length has to match `out`,
+    /// a mismatch means the state belongs to a different cartridge.
+    pub fn vec(&mut self, out: &mut [u8]) -> Result<(), io::Error> {
+        let len = try!(self.u32()) as usize;
+        if len != out.len() {
+            return Err(invalid("save state does not match this cartridge"));
+        }
+        self.bytes(out)
+    }
+
+    /// Checks that everything was read, leftovers mean the state is corrupt.
+    pub fn finish(&self) -> Result<(), io::Error> {
+        if self.pos != self.buf.len() {
+            return Err(invalid("save state has trailing data"));
+        }
+        Ok(())
+    }
+
+    pub fn header(&mut self) -> Result<(), io::Error> {
+        if try!(self.take(STATE_MAGIC.len())) != STATE_MAGIC {
+            return Err(invalid("not a save state"));
+        }
+        let version = try!(self.u32());
+        if version != STATE_VERSION {
+            return Err(invalid(&format!("unsupported save state version {}", version)));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_state() {
+    let mut w = Writer::new();
+    w.bytes(STATE_MAGIC);
+    w.u32(STATE_VERSION);
+    w.u16(0x1234);
+    w.vec(&[1, 2, 3]);
+    let buf = w.into_inner();
+
+    let mut r = Reader::new(&buf);
+    r.header().unwrap();
+    assert_eq!(r.u16().unwrap(), 0x1234);
+    let mut v = [0; 2];
+    assert!(r.vec(&mut v).is_err());
+    let mut v = [0; 3];
+    r.bytes(&mut v).unwrap();
+    assert_eq!(v, [1, 2, 3]);
+    r.finish().unwrap();
+    assert!(r.u8().is_err());
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use mem;
+use trace;
+
+/// Labels from an RGBDS/no$gmb `.sym` file, one `BANK:ADDR label` per line
+/// with `;` starting a comment.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    by_addr: HashMap<u16, Vec<(usize, String)>>,
+    by_name: HashMap<String, (usize, u16)>,
+}
+
+/// The symbol file rgblink writes for a rom, `game.sym` for `game.gb`.
+pub fn path(rom_path: &Path) -> PathBuf {
+    rom_path.with_extension("sym")
+}
+
+/// The rom bank mapped at `addr`, None outside of rom.
+pub fn bank_at(mm: &mem::MemoryMap, addr: u16) -> Option<usize> {
+    match addr {
+        0 ... 0x3fff => Some(0),
+        0x4000 ... 0x7fff => Some(mm.cart.rom_bank()),
+        _ => None,
+    }
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Parses a symbol file, skipping lines it does not understand.
+    pub fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap();
+            let mut words = line.split_whitespace();
+            let (loc, name) = match (words.next(), words.next()) {
+                (Some(loc), Some(name)) => (loc, name),
+                _ => continue,
+            };
+            let mut parts = loc.splitn(2, ':');
+            let bank = parts.next().and_then(|s| usize::from_str_radix(s, 16).ok());
+            let addr = parts.next().and_then(|s| u16::from_str_radix(s, 16).ok());
+            if let (Some(bank), Some(addr)) = (bank, addr) {
+                symbols.insert(bank, addr, name);
+            }
+        }
+        symbols
+    }
+
+    pub fn load(path: &Path) -> Result<Symbols, io::Error> {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)));
+        Ok(Symbols::parse(&text))
+    }
+
+    pub fn insert(&mut self, bank: usize, addr: u16, name: &str) {
+        self.by_addr.entry(addr).or_insert_with(Vec::new).push((bank, name.to_string()));
+        self.by_name.insert(name.to_string(), (bank, addr));
+    }
+
+    /// Number of distinct labels.
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// The label at `addr` in `bank`. Outside of rom (bank None) the first
+    /// label at the address is used, whatever its bank.
+    pub fn name(&self, bank: Option<usize>, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).and_then(|labels| {
+            labels.iter().find(|l| bank.is_none() || Some(l.0) == bank)
+        }).map(|l| l.1.as_str())
+    }
+
+    /// The label at `addr` as the cpu sees it right now, using the active
+    /// rom bank for 0x4000-0x7fff.
+    pub fn lookup(&self, mm: &mem::MemoryMap, addr: u16) -> Option<&str> {
+        self.name(bank_at(mm, addr), addr)
+    }
+
+    /// The bank and address of a label.
+    pub fn find(&self, name: &str) -> Option<(usize, u16)> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// Parses a label, or a hex address like `trace::parse_addr`. Labels
+    /// win so that one named `Fade` is not taken as an address.
+    pub fn parse_addr(&self, s: &str) -> Option<u16> {
+        self.find(s).map(|(_, addr)| addr).or_else(|| trace::parse_addr(s))
+    }
+
+    /// Like `parse_addr`, but keeps the bank of labels in 0x4000-0x7fff so
+    /// they only match while that bank is mapped.
+    pub fn parse_pc(&self, s: &str) -> Option<(Option<usize>, u16)> {
+        match self.find(s) {
+            Some((bank, addr)) if addr >= 0x4000 && addr < 0x8000 => Some((Some(bank), addr)),
+            Some((_, addr)) => Some((None, addr)),
+            None => trace::parse_addr(s).map(|addr| (None, addr)),
+        }
+    }
+}
+
+#[test]
+fn test_symbols() {
+    let symbols = Symbols::parse("; File generated by rgblink\n\
+                                  00:0150 Main\n\
+                                  00:0150 Main.loop ; same address\n\
+                                  01:4000 Init\n\
+                                  02:4000 Sound\n\
+                                  00:c000 wBuffer\n\
+                                  garbage\n");
+    assert_eq!(symbols.name(Some(0), 0x150), Some("Main"));
+    assert_eq!(symbols.name(Some(1), 0x4000), Some("Init"));
+    assert_eq!(symbols.name(Some(2), 0x4000), Some("Sound"));
+    assert_eq!(symbols.name(Some(3), 0x4000), None);
+    assert_eq!(symbols.name(None, 0xc000), Some("wBuffer"));
+    assert_eq!(symbols.find("Sound"), Some((2, 0x4000)));
+    assert_eq!(symbols.find("Main.loop"), Some((0, 0x150)));
+    assert_eq!(symbols.parse_addr("wBuffer"), Some(0xc000));
+    assert_eq!(symbols.parse_addr("$0100"), Some(0x100));
+    assert_eq!(symbols.parse_addr("Missing"), None);
+    assert_eq!(symbols.parse_pc("Sound"), Some((Some(2), 0x4000)));
+    assert_eq!(symbols.parse_pc("Main"), Some((None, 0x150)));
+    assert_eq!(symbols.parse_pc("4000"), Some((None, 0x4000)));
+    assert_eq!(path(Path::new("roms/game.gb")), PathBuf::from("roms/game.sym"));
+}
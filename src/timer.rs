@@ -1,16 +1,18 @@
 use std::fmt;
+use std::io;
 use cpu;
 use mem;
 use interrupt;
+use state;
 
 #[derive(Default)]
 pub struct Timer {
-	pub div : u8,  // Divider Register (R/W)
+	counter : u16, // internal divider, DIV (R/W) is the upper byte
 	pub tima : u8, // Timer Counter (R/W)
 	pub tma : u8,  // Timer Modulo (R/W)
 	pub tac : u8,  // Timer Control (R/W)
-	last_tick : u32,
-	last_div_tick : u32,
+	overflow : bool,  // TIMA overflowed last cycle and reads 0 until the reload
+	reloading : bool, // TIMA was reloaded from TMA this cycle
 }
 
 const TIMER_TAC_TIMER_STOP         : u8 = 1<<2;        // (0=Stop, 1=Start)
@@ -19,12 +21,24 @@ const TIMER_TAC_INPUT_CLOCK_SELECT : u8 = 1<<1 | 1<<0; // 00:   4096 Hz
                                                        // 10:  65536 Hz
                                                        // 11:  16384 Hz
 
+// TIMA counts falling edges of one divider bit, anded with the enable bit.
+// Since DIV writes and TAC writes can make that signal fall too, they can
+// increment TIMA, just like on the hardware.
+fn divider_bit(tac: u8) -> u16 {
+    match tac & TIMER_TAC_INPUT_CLOCK_SELECT {
+        0 => 1<<9,
+        1 => 1<<3,
+        2 => 1<<5,
+        _ => 1<<7,
+    }
+}
+
 impl fmt::Debug for Timer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Timer {{ div:{:02x} tima:{:02x} tma:{:02x} tac:{:02x} \
-               last_tick:{:02x} last_div_tick:{:02x} }}",
-               self.div, self.tima, self.tma, self.tac, self.last_tick,
-               self.last_div_tick)
+        write!(f, "Timer {{ counter:{:04x} tima:{:02x} tma:{:02x} tac:{:02x} \
+               overflow:{} reloading:{} }}",
+               self.counter, self.tima, self.tma, self.tac, self.overflow,
+               self.reloading)
     }
 }
 
@@ -35,41 +49,95 @@ impl Timer {
         return timer;
     }
 
-    pub fn run(&mut self, mm: &mut mem::MemoryMap, cycles: u32) {
-        // increment div (always 16384 Hz)
-        self.last_div_tick += cycles;
-        if self.last_div_tick >= 256 {
-            self.last_div_tick -= 256;
-            self.div = self.div.wrapping_add(1);
+    pub fn save_state(&self, w: &mut state::Writer) {
+        w.u16(self.counter);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.bool(self.overflow);
+        w.bool(self.reloading);
+    }
+
+    pub fn load_state(&mut self, r: &mut state::Reader) -> Result<(), io::Error> {
+        self.counter = try!(r.u16());
+        self.tima = try!(r.u8());
+        self.tma = try!(r.u8());
+        self.tac = try!(r.u8());
+        self.overflow = try!(r.bool());
+        self.reloading = try!(r.bool());
+        Ok(())
+    }
+
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    fn signal(&self) -> bool {
+        self.tac & TIMER_TAC_TIMER_STOP > 0 && self.counter & divider_bit(self.tac) > 0
+    }
+
+    fn increment(&mut self) {
+        self.tima = self.tima.wrapping_add(1);
+        if self.tima == 0 {
+            self.overflow = true;
+        }
+    }
+
+    /// Any write to DIV resets the whole divider.
+    pub fn write_div(&mut self) {
+        let before = self.signal();
+        self.counter = 0;
+        if before {
+            self.increment();
+        }
+    }
+
+    pub fn write_tima(&mut self, val: u8) {
+        // ignored on the reload cycle, cancels a pending reload before it
+        if !self.reloading {
+            self.tima = val;
+            self.overflow = false;
         }
+    }
 
-        if self.tac & TIMER_TAC_TIMER_STOP == 0 {
-            return
+    pub fn write_tma(&mut self, val: u8) {
+        self.tma = val;
+        if self.reloading {
+            self.tima = val;
         }
+    }
+
+    pub fn read_tac(&self) -> u8 {
+        self.tac | 0xf8
+    }
 
-        let cycles_per_tick = match self.tac & TIMER_TAC_INPUT_CLOCK_SELECT {
-            0 => 1024,
-            1 => 16,
-            2 => 64,
-            3 => 25,
-            _ => panic!("bad tac {}", self.tac),
-        };
-
-        // increment tima based on selected clock
-        self.last_tick += cycles;
-        if self.last_tick >= cycles_per_tick {
-            self.last_tick -= cycles_per_tick;
-            self.tima = self.tima.wrapping_add(1);
-
-                //println!("tima = {} flag={:02x} master={}!", self.tima, mm.interrupt_flag, mm.interrupt_master_enable);
-            // handle overflow
-            if self.tima == 0 {
-                //println!("time = 0!");
+    pub fn write_tac(&mut self, val: u8) {
+        let before = self.signal();
+        self.tac = val & (TIMER_TAC_TIMER_STOP | TIMER_TAC_INPUT_CLOCK_SELECT);
+        if before && !self.signal() {
+            self.increment();
+        }
+    }
+
+    /// Advances the divider one machine cycle (4 clocks) at a time. After
+    /// TIMA overflows it reads 0 for a cycle, then it is reloaded from TMA
+    /// and the interrupt is requested.
+    pub fn run(&mut self, mm: &mut mem::MemoryMap, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            self.reloading = false;
+            if self.overflow {
+                self.overflow = false;
+                self.reloading = true;
                 self.tima = self.tma;
-                //if mm.interrupt_master_enable {
-                    mm.interrupt_flag |= interrupt::INTERRUPT_TIMER;
-                //}
+                mm.interrupt_flag |= interrupt::INTERRUPT_TIMER;
             }
+            let before = self.signal();
+            self.counter = self.counter.wrapping_add(4);
+            if before && !self.signal() {
+                self.increment();
+            }
+            cycles = cycles.saturating_sub(4);
         }
     }
 }
@@ -77,5 +145,53 @@ impl Timer {
 #[test]
 fn test_timer() {
     let timer = Timer::new();
-    assert_eq!(timer.div, 0);
+    assert_eq!(timer.div(), 0);
+
+    let mut mm = mem::MemoryMap::for_test(vec![0; 0x8000]);
+    let mut timer = Timer::new();
+    timer.run(&mut mm, 1024);
+    assert_eq!(timer.div(), 4);
+    timer.write_div();
+    assert_eq!(timer.div(), 0);
+
+    // 16 clocks per increment, big steps don't lose any
+    timer.write_tac(0x05);
+    timer.run(&mut mm, 160);
+    assert_eq!(timer.tima, 10);
+
+    // the divider bit falls when div is reset and when the timer is stopped
+    timer.run(&mut mm, 8);
+    timer.write_div();
+    assert_eq!(timer.tima, 11);
+    timer.run(&mut mm, 8);
+    timer.write_tac(0x01);
+    assert_eq!(timer.tima, 12);
+
+    // overflow: 0 for one cycle, then tma and the interrupt
+    timer.write_tac(0x05);
+    timer.tima = 0xff;
+    timer.write_tma(0x80);
+    mm.interrupt_flag = 0;
+    while timer.tima != 0 {
+        timer.run(&mut mm, 4);
+    }
+    assert_eq!(mm.interrupt_flag, 0);
+    timer.run(&mut mm, 4);
+    assert_eq!(timer.tima, 0x80);
+    assert_eq!(mm.interrupt_flag, interrupt::INTERRUPT_TIMER);
+    // writes to tima on the reload cycle are ignored, tma goes through
+    timer.write_tima(0x12);
+    timer.write_tma(0x90);
+    assert_eq!(timer.tima, 0x90);
+
+    // writing tima during the zero cycle cancels the reload
+    timer.tima = 0xff;
+    mm.interrupt_flag = 0;
+    while timer.tima != 0 {
+        timer.run(&mut mm, 4);
+    }
+    timer.write_tima(0x33);
+    timer.run(&mut mm, 4);
+    assert_eq!(timer.tima, 0x33);
+    assert_eq!(mm.interrupt_flag, 0);
 }
@@ -0,0 +1,159 @@
+use std::io::prelude::*;
+use std::io;
+use std::rc::Rc;
+
+use cpu;
+use mem;
+use symbols::{self, Symbols};
+
+/// Writes one line per executed instruction in the format used by
+/// gameboy-doctor and most other emulators:
+///
+/// ```text
+/// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+/// ```
+///
+/// Logging starts when pc first reaches `start` and stops for good after the
+/// line for `stop`. Both are a rom bank and an address, with the bank only
+/// checked when it is given. Without a start address it starts right away. With
+/// labels turned on, a `label:` line is written before instructions that
+/// have one; that breaks line by line diffs against gameboy-doctor logs.
+pub struct Trace {
+    out: Box<Write>,
+    start: Option<(Option<usize>, u16)>,
+    stop: Option<(Option<usize>, u16)>,
+    active: bool,
+    symbols: Rc<Symbols>,
+    labels: bool,
+}
+
+/// Parses a hex address, with or without a `$` or `0x` prefix.
+pub fn parse_addr(s: &str) -> Option<u16> {
+    let s = s.trim_left_matches('$').trim_left_matches("0x");
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn reached(mm: &mem::MemoryMap, pc: u16, at: Option<(Option<usize>, u16)>) -> bool {
+    match at {
+        Some((bank, addr)) => addr == pc && (bank.is_none() || bank == symbols::bank_at(mm, pc)),
+        None => false,
+    }
+}
+
+impl Trace {
+    pub fn new(out: Box<Write>, start: Option<(Option<usize>, u16)>,
+               stop: Option<(Option<usize>, u16)>) -> Trace {
+        Trace {
+            out: out,
+            start: start,
+            stop: stop,
+            active: start.is_none(),
+            symbols: Rc::new(Symbols::new()),
+            labels: false,
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: Rc<Symbols>) {
+        self.symbols = symbols;
+    }
+
+    /// Writes a `label:` line before instructions that have a label.
+    pub fn set_labels(&mut self, labels: bool) {
+        self.labels = labels;
+    }
+
+    /// Logs the instruction the cpu is about to execute. Reading PCMEM does
+    /// not tick the system.
+    pub fn log(&mut self, regs: &cpu::Registers, mm: &mut mem::MemoryMap) -> Result<(), io::Error> {
+        if !self.active {
+            if !reached(mm, regs.pc, self.start) {
+                return Ok(());
+            }
+            self.active = true;
+            self.start = None;
+        }
+        let pc = regs.pc;
+        if self.labels {
+            if let Some(name) = self.symbols.lookup(mm, pc) {
+                try!(writeln!(self.out, "{}:", name));
+            }
+        }
+        try!(writeln!(self.out, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} \
+                      H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                      regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l,
+                      regs.sp, pc,
+                      mm.read(pc), mm.read(pc.wrapping_add(1)),
+                      mm.read(pc.wrapping_add(2)), mm.read(pc.wrapping_add(3))));
+        if reached(mm, pc, self.stop) {
+            self.active = false;
+            try!(self.out.flush());
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.out.flush()
+    }
+}
+
+/// A writer for tests that keeps what was written, shared with its clones.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct SharedBuf(Rc<::std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuf {
+    pub fn new() -> SharedBuf {
+        SharedBuf::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.borrow_mut().write(buf) }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn test_trace() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x13, 0x02]);
+    let mut mm = mem::MemoryMap::for_test(rom);
+    let buf = SharedBuf::new();
+    let start = parse_addr("$0100").map(|addr| (None, addr));
+    let stop = parse_addr("0x0100").map(|addr| (Some(0), addr));
+    let mut trace = Trace::new(Box::new(buf.clone()), start, stop);
+    let mut regs = cpu::Cpu::new().registers();
+    regs.pc = 0xff;
+    trace.log(&regs, &mut mm).unwrap();
+    regs.pc = 0x100;
+    trace.log(&regs, &mut mm).unwrap();
+    trace.log(&regs, &mut mm).unwrap();
+    assert_eq!(String::from_utf8(buf.contents()).unwrap(),
+               "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02\n");
+
+    buf.clear();
+    let mut trace = Trace::new(Box::new(buf.clone()), None, None);
+    trace.set_symbols(Rc::new(Symbols::parse("00:0100 Start\n")));
+    trace.log(&regs, &mut mm).unwrap();
+    assert!(String::from_utf8(buf.contents()).unwrap().starts_with("A:01"));
+    buf.clear();
+    trace.set_labels(true);
+    trace.log(&regs, &mut mm).unwrap();
+    assert!(String::from_utf8(buf.contents()).unwrap().starts_with("Start:\nA:01"));
+
+    // a start in bank 2 doesn't fire while bank 1 is mapped
+    buf.clear();
+    let mut trace = Trace::new(Box::new(buf.clone()), Some((Some(2), 0x4000)), None);
+    regs.pc = 0x4000;
+    trace.log(&regs, &mut mm).unwrap();
+    assert!(buf.contents().is_empty());
+}
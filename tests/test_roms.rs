@@ -0,0 +1,171 @@
+// Runs test roms from a local directory, one test case per rom. The roms are
+// not part of the repository; point RUSTBOY_TEST_ROMS at a directory with
+// blargg's and/or mooneye's test suites (default tests/roms):
+//
+//   RUSTBOY_TEST_ROMS=~/gb-test-roms cargo test --test test_roms [FILTER]
+//
+// A rom passes or fails by
+//
+// - blargg: printing "Passed" or "Failed" on the serial port,
+// - mooneye: executing `ld b, b` with the fibonacci numbers 3/5/8/13/21/34
+//   in b/c/d/e/h/l (0x42 everywhere means failure),
+// - screen hash: a `<rom>.hash` file next to the rom holding the expected
+//   hash of the screen and optionally the number of frames to run (default
+//   300). Failing runs print the hash they got.
+//
+// Roms give up after RUSTBOY_TEST_FRAMES frames (default 7200, two minutes
+// of emulated time).
+
+extern crate rustboy;
+
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+
+use rustboy::Gameboy;
+use rustboy::gameboy::CYCLES_PER_FRAME;
+use rustboy::serial::SerialDevice;
+
+const FIBONACCI : [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// Collects what the rom prints on the serial port.
+struct Capture(Rc<RefCell<String>>);
+
+impl SerialDevice for Capture {
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.0.borrow_mut().push(out as char);
+        0xff
+    }
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().map_or(false, |ext| ext == "gb") {
+            roms.push(path);
+        }
+    }
+}
+
+// FNV-1a, good enough to tell screens apart.
+fn hash(pixels: &[u8]) -> u64 {
+    pixels.iter().fold(0xcbf29ce484222325, |h, p| (h ^ *p as u64).wrapping_mul(0x100000001b3))
+}
+
+fn run_screen_hash(gb: &mut Gameboy, spec: &str) -> Result<(), String> {
+    let mut words = spec.split_whitespace();
+    let expected = words.next().and_then(|s| u64::from_str_radix(s, 16).ok());
+    let frames = words.next().and_then(|s| s.parse().ok()).unwrap_or(300);
+    for _ in 0..frames {
+        gb.run_frame();
+        if let Some(e) = gb.lockup_event() {
+            return Err(format!("{}", e));
+        }
+    }
+    let actual = hash(gb.framebuffer());
+    match expected {
+        Some(expected) if expected == actual => Ok(()),
+        _ => Err(format!("screen hash {:016x} after {} frames", actual, frames)),
+    }
+}
+
+fn run_until_done(gb: &mut Gameboy, frames: u64) -> Result<(), String> {
+    let serial = Rc::new(RefCell::new(String::new()));
+    gb.connect_serial(Box::new(Capture(serial.clone())));
+    let mut elapsed = 0u64;
+
+    while elapsed < frames * CYCLES_PER_FRAME as u64 {
+        let prev = gb.cycles();
+        gb.step();
+        elapsed += gb.cycles().wrapping_sub(prev) as u64;
+
+        if let Some(e) = gb.lockup_event() {
+            return Err(format!("{}\n{}", e, serial.borrow()));
+        }
+        if serial.borrow().contains("Passed") {
+            return Ok(());
+        }
+        if serial.borrow().contains("Failed") {
+            return Err(serial.borrow().clone());
+        }
+
+        let r = gb.cpu.registers();
+        if gb.mm.read(r.pc) == 0x40 {
+            let regs = [r.b, r.c, r.d, r.e, r.h, r.l];
+            if regs == FIBONACCI {
+                return Ok(());
+            }
+            if regs.iter().all(|r| *r == 0x42) {
+                return Err(format!("mooneye failure signature at pc={:04x}", r.pc));
+            }
+        }
+    }
+    let output = serial.borrow();
+    Err(format!("timed out after {} frames\n{}", frames, output))
+}
+
+fn run_rom(path: &Path, frames: u64) -> Result<(), String> {
+    let mut rom = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut rom)).map_err(|e| format!("{}", e)));
+    let mut gb = try!(Gameboy::new(rom).map_err(|e| format!("{}", e)));
+
+    let mut spec = String::new();
+    if File::open(path.with_extension("hash")).and_then(|mut f| f.read_to_string(&mut spec)).is_ok() {
+        run_screen_hash(&mut gb, &spec)
+    } else {
+        run_until_done(&mut gb, frames)
+    }
+}
+
+fn main() {
+    let dir = PathBuf::from(env::var("RUSTBOY_TEST_ROMS").unwrap_or(String::from("tests/roms")));
+    let frames = env::var("RUSTBOY_TEST_FRAMES").ok().and_then(|s| s.parse().ok()).unwrap_or(7200);
+    // cargo test passes the filter and its own flags through
+    let filter = env::args().skip(1).find(|arg| !arg.starts_with('-'));
+
+    let mut roms = Vec::new();
+    find_roms(&dir, &mut roms);
+    roms.sort();
+    let roms : Vec<PathBuf> = roms.into_iter().filter(|path| {
+        filter.as_ref().map_or(true, |f| path.to_string_lossy().contains(f.as_str()))
+    }).collect();
+    if roms.is_empty() {
+        println!("no test roms in {}, set RUSTBOY_TEST_ROMS", dir.display());
+    }
+
+    println!("running {} tests", roms.len());
+    let mut failures = Vec::new();
+    for path in &roms {
+        let name = path.strip_prefix(&dir).unwrap_or(path).display().to_string();
+        match run_rom(path, frames) {
+            Ok(()) => println!("test {} ... ok", name),
+            Err(e) => {
+                println!("test {} ... FAILED", name);
+                failures.push((name, e));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:\n");
+        for &(ref name, ref e) in &failures {
+            println!("---- {} ----\n{}\n", name, e.trim_right());
+        }
+    }
+    println!("\ntest result: {}. {} passed; {} failed",
+             if failures.is_empty() { "ok" } else { "FAILED" },
+             roms.len() - failures.len(), failures.len());
+    if !failures.is_empty() {
+        process::exit(101);
+    }
+}